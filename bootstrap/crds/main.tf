@@ -64,6 +64,33 @@ resource "kubernetes_manifest" "customresourcedefinition_ogmiosports_demeter_run
                       "nullable" = true
                       "type" = "string"
                     }
+                    "authTokenGeneration" = {
+                      "format" = "uint32"
+                      "minimum" = 0
+                      "nullable" = true
+                      "type" = "integer"
+                    }
+                    "authTokens" = {
+                      "default" = []
+                      "items" = {
+                        "properties" = {
+                          "notAfter" = {
+                            "description" = "Time is a wrapper around time.Time which supports correct marshaling to YAML and JSON.  Wrappers are provided for many of the factory methods that the time package offers."
+                            "format" = "date-time"
+                            "nullable" = true
+                            "type" = "string"
+                          }
+                          "token" = {
+                            "type" = "string"
+                          }
+                        }
+                        "required" = [
+                          "token",
+                        ]
+                        "type" = "object"
+                      }
+                      "type" = "array"
+                    }
                     "network" = {
                       "type" = "string"
                     }
@@ -89,6 +116,27 @@ resource "kubernetes_manifest" "customresourcedefinition_ogmiosports_demeter_run
                     "authToken" = {
                       "type" = "string"
                     }
+                    "authTokens" = {
+                      "default" = []
+                      "items" = {
+                        "properties" = {
+                          "notAfter" = {
+                            "description" = "Time is a wrapper around time.Time which supports correct marshaling to YAML and JSON.  Wrappers are provided for many of the factory methods that the time package offers."
+                            "format" = "date-time"
+                            "nullable" = true
+                            "type" = "string"
+                          }
+                          "token" = {
+                            "type" = "string"
+                          }
+                        }
+                        "required" = [
+                          "token",
+                        ]
+                        "type" = "object"
+                      }
+                      "type" = "array"
+                    }
                     "authenticatedEndpointUrl" = {
                       "type" = "string"
                     }
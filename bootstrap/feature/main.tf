@@ -28,6 +28,12 @@ variable "api_key_salt" {
   default = "ogmios-salt"
 }
 
+// seconds a replaced auth token stays valid
+variable "api_key_grace_period" {
+  type    = number
+  default = 604800
+}
+
 variable "dcu_per_frame" {
   type = map(string)
   default = {
@@ -66,6 +66,11 @@ resource "kubernetes_deployment_v1" "ogmios_operator" {
             value = var.api_key_salt
           }
 
+          env {
+            name  = "API_KEY_GRACE_PERIOD"
+            value = var.api_key_grace_period
+          }
+
           env {
             name  = "DCU_PER_FRAME"
             value = "mainnet=${var.dcu_per_frame["mainnet"]},preprod=${var.dcu_per_frame["preprod"]},preview=${var.dcu_per_frame["preview"]},vector-testnet=${var.dcu_per_frame["vector-testnet"]}"
@@ -24,6 +24,12 @@ module "ogmios_v1_feature" {
   dcu_per_frame      = var.dcu_per_frame
 }
 
+module "ogmios_v1_proxy_redis" {
+  depends_on = [kubernetes_namespace.namespace]
+  source     = "./redis"
+  namespace  = var.namespace
+}
+
 module "ogmios_v1_proxy" {
   depends_on      = [kubernetes_namespace.namespace]
   source          = "./proxy"
@@ -32,6 +38,8 @@ module "ogmios_v1_proxy" {
   proxy_image_tag = var.proxy_blue_image_tag
   extension_name  = var.extension_name
   networks        = var.networks
+  extra_dns_zones = var.proxy_extra_dns_zones
+  redis_url       = module.ogmios_v1_proxy_redis.url
   name            = "proxy"
 }
 
@@ -43,6 +51,8 @@ module "ogmios_v1_proxy_green" {
   proxy_image_tag = var.proxy_green_image_tag
   extension_name  = var.extension_name
   networks        = ["mainnet", "preprod", "preview", "vector-testnet"]
+  extra_dns_zones = var.proxy_extra_dns_zones
+  redis_url       = module.ogmios_v1_proxy_redis.url
   environment     = "green"
   name            = "proxy-green"
 }
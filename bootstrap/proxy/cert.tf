@@ -1,15 +1,20 @@
-
-
 locals {
-  by_network = flatten([
-    for network in var.networks : [
-      for version in var.versions : "*.${network}-v${version}.${var.extension_name}.${var.dns_zone}"
-    ]
-  ])
+  # Zone of every certificate served by the proxy, the first one is the main zone
+  dns_zones = concat([var.dns_zone], var.extra_dns_zones)
+
+  dns_names_by_zone = {
+    for zone in local.dns_zones : zone => concat(
+      [for combo in setproduct(var.networks, var.versions) : "*.${combo[0]}-v${combo[1]}.${var.extension_name}.${zone}"],
+      # Add the extra URL to the list of generated URLs
+      ["*.${var.extension_name}.${zone}"]
+    )
+  }
 
-  # Add the extra URL to the list of generated URLs
-  dns_names        = concat(local.by_network, ["*.${var.extension_name}.${var.dns_zone}"])
   cert_secret_name = var.environment != null ? "${var.extension_name}-${var.environment}-wildcard-tls" : "${var.extension_name}-wildcard-tls"
+  cert_secret_names = merge(
+    { (var.dns_zone) = local.cert_secret_name },
+    { for zone in var.extra_dns_zones : zone => "${local.cert_secret_name}-${replace(zone, ".", "-")}" }
+  )
 }
 
 resource "kubernetes_manifest" "certificate_cluster_wildcard_tls" {
@@ -21,7 +26,7 @@ resource "kubernetes_manifest" "certificate_cluster_wildcard_tls" {
       "namespace" = var.namespace
     }
     "spec" = {
-      "dnsNames" = local.dns_names
+      "dnsNames" = local.dns_names_by_zone[var.dns_zone]
 
       "issuerRef" = {
         "kind" = "ClusterIssuer"
@@ -31,3 +36,25 @@ resource "kubernetes_manifest" "certificate_cluster_wildcard_tls" {
     }
   }
 }
+
+resource "kubernetes_manifest" "certificate_extra_zone_wildcard_tls" {
+  for_each = toset(var.extra_dns_zones)
+
+  manifest = {
+    "apiVersion" = "cert-manager.io/v1"
+    "kind"       = "Certificate"
+    "metadata" = {
+      "name"      = local.cert_secret_names[each.value]
+      "namespace" = var.namespace
+    }
+    "spec" = {
+      "dnsNames" = local.dns_names_by_zone[each.value]
+
+      "issuerRef" = {
+        "kind" = "ClusterIssuer"
+        "name" = "letsencrypt"
+      }
+      "secretName" = local.cert_secret_names[each.value]
+    }
+  }
+}
@@ -5,6 +5,7 @@ locals {
     {
       "name"            = "0",
       "max_connections" = 2
+      "denied_methods"  = ["submitTransaction", "evaluateTransaction", "SubmitTx", "EvaluateTx"]
       "rates" = [
         {
           "interval" = "1m",
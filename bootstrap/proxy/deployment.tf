@@ -1,6 +1,9 @@
 resource "kubernetes_deployment_v1" "ogmios_proxy" {
   wait_for_rollout = false
-  depends_on       = [kubernetes_manifest.certificate_cluster_wildcard_tls]
+  depends_on = [
+    kubernetes_manifest.certificate_cluster_wildcard_tls,
+    kubernetes_manifest.certificate_extra_zone_wildcard_tls,
+  ]
 
   metadata {
     name      = local.name
@@ -18,6 +21,9 @@ resource "kubernetes_deployment_v1" "ogmios_proxy" {
         labels = local.proxy_labels
       }
       spec {
+        // Room for the proxy to close the sessions left after the drain.
+        termination_grace_period_seconds = var.prestop_delay + var.drain_timeout + 15
+
         container {
           name              = "main"
           image             = "ghcr.io/demeter-run/ext-cardano-ogmios-proxy:${var.proxy_image_tag}"
@@ -77,13 +83,36 @@ resource "kubernetes_deployment_v1" "ogmios_proxy" {
           }
 
           env {
-            name  = "SSL_CRT_PATH"
-            value = "/certs/tls.crt"
+            name  = "SSL_CERTS_PATH"
+            value = "/certs"
+          }
+
+          dynamic "env" {
+            for_each = var.redis_url != null ? [var.redis_url] : []
+            content {
+              name  = "PROXY_REDIS_URL"
+              value = env.value
+            }
           }
 
           env {
-            name  = "SSL_KEY_PATH"
-            value = "/certs/tls.key"
+            name  = "PROXY_PRESTOP_DELAY"
+            value = var.prestop_delay
+          }
+
+          env {
+            name  = "PROXY_DRAIN_TIMEOUT"
+            value = var.drain_timeout
+          }
+
+          // Served on the metrics port, which doesn't need TLS.
+          readiness_probe {
+            http_get {
+              path = "/healthz"
+              port = "metrics"
+            }
+            period_seconds    = 5
+            failure_threshold = 1
           }
 
           volume_mount {
@@ -99,8 +128,23 @@ resource "kubernetes_deployment_v1" "ogmios_proxy" {
 
         volume {
           name = "certs"
-          secret {
-            secret_name = local.cert_secret_name
+          projected {
+            dynamic "sources" {
+              for_each = local.cert_secret_names
+              content {
+                secret {
+                  name = sources.value
+                  items {
+                    key  = "tls.crt"
+                    path = "${sources.key}.crt"
+                  }
+                  items {
+                    key  = "tls.key"
+                    path = "${sources.key}.key"
+                  }
+                }
+              }
+            }
           }
         }
 
@@ -61,6 +61,26 @@ variable "ogmios_port" {
   default = 1337
 }
 
+// Redis shared by the replicas for the rate limits, connection leases and quotas. Without it
+// each replica keeps its own counters.
+variable "redis_url" {
+  type    = string
+  default = null
+}
+
+// Seconds the proxy keeps accepting connections after SIGTERM while not ready, so the
+// endpoint is removed from the service before the listener closes.
+variable "prestop_delay" {
+  type    = number
+  default = 10
+}
+
+// Seconds live sessions are kept after SIGTERM before being closed.
+variable "drain_timeout" {
+  type    = number
+  default = 60
+}
+
 
 variable "extension_name" {
   type = string
@@ -80,3 +100,9 @@ variable "dns_zone" {
   type    = string
   default = "demeter.run"
 }
+
+// zones served besides dns_zone, each with its own certificate
+variable "extra_dns_zones" {
+  type    = list(string)
+  default = []
+}
@@ -0,0 +1,158 @@
+// Redis shared by the proxy replicas, blue and green included, for the rate limits, the
+// connection leases and the request quotas. The append only file is kept in a volume, so the
+// quotas survive restarts of Redis too.
+
+variable "namespace" {
+  description = "the namespace where the resources will be created"
+}
+
+variable "name" {
+  type    = string
+  default = "proxy-redis"
+}
+
+variable "image" {
+  type    = string
+  default = "redis:7.2-alpine"
+}
+
+variable "storage_size" {
+  type    = string
+  default = "1Gi"
+}
+
+variable "storage_class_name" {
+  type    = string
+  default = null
+}
+
+locals {
+  port   = 6379
+  labels = { role = var.name }
+}
+
+resource "kubernetes_persistent_volume_claim_v1" "redis" {
+  wait_until_bound = false
+
+  metadata {
+    name      = var.name
+    namespace = var.namespace
+  }
+
+  spec {
+    access_modes       = ["ReadWriteOnce"]
+    storage_class_name = var.storage_class_name
+
+    resources {
+      requests = {
+        storage = var.storage_size
+      }
+    }
+  }
+}
+
+resource "kubernetes_deployment_v1" "redis" {
+  wait_for_rollout = false
+
+  metadata {
+    name      = var.name
+    namespace = var.namespace
+    labels    = local.labels
+  }
+  spec {
+    replicas = 1
+
+    // The volume can only be mounted by one pod at a time.
+    strategy {
+      type = "Recreate"
+    }
+
+    selector {
+      match_labels = local.labels
+    }
+    template {
+      metadata {
+        name   = var.name
+        labels = local.labels
+      }
+      spec {
+        container {
+          name              = "main"
+          image             = var.image
+          image_pull_policy = "IfNotPresent"
+          args              = ["--appendonly", "yes", "--save", ""]
+
+          port {
+            name           = "redis"
+            container_port = local.port
+            protocol       = "TCP"
+          }
+
+          readiness_probe {
+            tcp_socket {
+              port = "redis"
+            }
+            period_seconds = 5
+          }
+
+          volume_mount {
+            mount_path = "/data"
+            name       = "data"
+          }
+        }
+
+        volume {
+          name = "data"
+          persistent_volume_claim {
+            claim_name = kubernetes_persistent_volume_claim_v1.redis.metadata.0.name
+          }
+        }
+
+        toleration {
+          effect   = "NoSchedule"
+          key      = "demeter.run/compute-profile"
+          operator = "Equal"
+          value    = "general-purpose"
+        }
+
+        toleration {
+          effect   = "NoSchedule"
+          key      = "demeter.run/compute-arch"
+          operator = "Equal"
+          value    = "x86"
+        }
+
+        toleration {
+          effect   = "NoSchedule"
+          key      = "demeter.run/availability-sla"
+          operator = "Equal"
+          value    = "consistent"
+        }
+      }
+    }
+  }
+}
+
+resource "kubernetes_service_v1" "redis" {
+  metadata {
+    name      = var.name
+    namespace = var.namespace
+  }
+
+  spec {
+    selector = local.labels
+
+    port {
+      name        = "redis"
+      port        = local.port
+      target_port = local.port
+      protocol    = "TCP"
+    }
+
+    type = "ClusterIP"
+  }
+}
+
+output "url" {
+  value = "redis://${var.name}.${var.namespace}.svc.cluster.local:${local.port}"
+}
@@ -7,6 +7,11 @@ variable "dns_zone" {
   default = "demeter.run"
 }
 
+variable "proxy_extra_dns_zones" {
+  type    = list(string)
+  default = []
+}
+
 variable "extension_name" {
   type    = string
   default = "ogmios-m1"
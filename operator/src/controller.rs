@@ -1,4 +1,6 @@
+use chrono::{DateTime, Utc};
 use futures::StreamExt;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
 use kube::{
     api::ListParams,
     runtime::{controller::Action, watcher::Config as WatcherConfig, Controller},
@@ -9,7 +11,9 @@ use serde::{Deserialize, Serialize};
 use std::{sync::Arc, time::Duration};
 use tracing::{error, info, instrument};
 
-use crate::{build_api_key, build_hostname, patch_resource_status, Error, Metrics, Result, State};
+use crate::{
+    build_api_key, build_hostname, get_config, patch_resource_status, Error, Metrics, Result, State,
+};
 
 pub static OGMIOS_PORT_FINALIZER: &str = "ogmiosports.demeter.run";
 
@@ -38,6 +42,11 @@ pub struct OgmiosPortSpec {
     // throughput should be 0, 1, 2
     pub throughput_tier: String,
     pub auth_token: Option<String>,
+    // extra tokens accepted besides the ones issued by the operator
+    #[serde(default)]
+    pub auth_tokens: Vec<AuthToken>,
+    // increasing it issues a new auth token, the previous one stays valid for the grace period
+    pub auth_token_generation: Option<u32>,
 }
 
 #[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
@@ -46,6 +55,17 @@ pub struct OgmiosPortStatus {
     pub endpoint_url: String,
     pub authenticated_endpoint_url: String,
     pub auth_token: String,
+    // tokens issued by the operator, the current one and the previous ones in their grace period
+    #[serde(default)]
+    pub auth_tokens: Vec<AuthToken>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthToken {
+    pub token: String,
+    // the token is refused from this time on, it never expires when not set
+    pub not_after: Option<Time>,
 }
 
 struct Context {
@@ -65,11 +85,20 @@ async fn reconcile(crd: Arc<OgmiosPort>, ctx: Arc<Context>) -> Result<Action> {
     };
 
     let (hostname, hostname_key) = build_hostname(&crd.spec.network, &crd.spec.version, &key);
+    let auth_tokens = issue_auth_tokens(crd.status.as_ref(), &key, Utc::now());
+
+    // Expired tokens are removed from the status once their grace period is over.
+    let next_expiry = auth_tokens
+        .iter()
+        .filter_map(|token| token.not_after.as_ref())
+        .map(|not_after| not_after.0)
+        .min();
 
     let status = OgmiosPortStatus {
         endpoint_url: format!("https://{hostname}",),
         authenticated_endpoint_url: format!("https://{hostname_key}"),
         auth_token: key,
+        auth_tokens,
     };
 
     let namespace = crd.namespace().unwrap();
@@ -86,7 +115,53 @@ async fn reconcile(crd: Arc<OgmiosPort>, ctx: Arc<Context>) -> Result<Action> {
 
     info!(resource = crd.name_any(), "Reconcile completed");
 
-    Ok(Action::await_change())
+    match next_expiry {
+        Some(next_expiry) => Ok(Action::requeue(
+            (next_expiry - Utc::now()).to_std().unwrap_or_default(),
+        )),
+        None => Ok(Action::await_change()),
+    }
+}
+
+/// Tokens issued to the port. The current token never expires, the tokens it replaced stay
+/// valid until the end of their grace period.
+fn issue_auth_tokens(
+    status: Option<&OgmiosPortStatus>,
+    key: &str,
+    now: DateTime<Utc>,
+) -> Vec<AuthToken> {
+    let mut auth_tokens = vec![AuthToken {
+        token: key.to_string(),
+        not_after: None,
+    }];
+
+    let previous = match status {
+        // Statuses written before the token list only have the current token.
+        Some(status) if status.auth_tokens.is_empty() => vec![AuthToken {
+            token: status.auth_token.clone(),
+            not_after: None,
+        }],
+        Some(status) => status.auth_tokens.clone(),
+        None => vec![],
+    };
+
+    let grace_period = chrono::Duration::from_std(get_config().api_key_grace_period)
+        .unwrap_or(chrono::Duration::zero());
+    for token in previous {
+        if token.token == key {
+            continue;
+        }
+
+        let not_after = token.not_after.unwrap_or(Time(now + grace_period));
+        if not_after.0 > now {
+            auth_tokens.push(AuthToken {
+                token: token.token,
+                not_after: Some(not_after),
+            });
+        }
+    }
+
+    auth_tokens
 }
 
 fn error_policy(crd: Arc<OgmiosPort>, err: &Error, ctx: Arc<Context>) -> Action {
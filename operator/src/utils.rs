@@ -42,7 +42,13 @@ pub async fn build_api_key(crd: &OgmiosPort) -> Result<String, Error> {
     let namespace = crd.namespace().unwrap();
     let name = format!("ogmios-auth-{}", &crd.name_any());
 
-    let password = format!("{}{}", name, namespace).as_bytes().to_vec();
+    // Keys of later generations replace the first one when the port rotates its key.
+    let password = match crd.spec.auth_token_generation {
+        Some(generation) if generation > 0 => format!("{}{}{}", name, namespace, generation),
+        _ => format!("{}{}", name, namespace),
+    }
+    .as_bytes()
+    .to_vec();
 
     let config = get_config();
     let salt = config.api_key_salt.as_bytes();
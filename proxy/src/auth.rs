@@ -10,7 +10,76 @@ use std::{collections::HashMap, sync::Arc};
 use tokio::pin;
 use tracing::{error, info, instrument};
 
-use crate::{Consumer, State};
+use crate::{Consumer, ConsumerToken, State};
+
+/// Every token of the port: the ones issued by the operator, including the previous ones in
+/// their grace period, and the extra ones of the spec.
+fn consumer_tokens(crd: &OgmiosPort, consumer: &Consumer) -> HashMap<String, ConsumerToken> {
+    let mut tokens: HashMap<String, ConsumerToken> = HashMap::new();
+    let Some(status) = &crd.status else {
+        return tokens;
+    };
+
+    let current = (status.auth_token.clone(), None);
+    let listed = status
+        .auth_tokens
+        .iter()
+        .chain(&crd.spec.auth_tokens)
+        .map(|token| {
+            let not_after = token.not_after.as_ref().map(|t| t.0.timestamp_millis());
+            (token.token.clone(), not_after)
+        });
+
+    for (token, not_after) in std::iter::once(current).chain(listed) {
+        let entry = tokens.entry(token).or_insert(ConsumerToken {
+            consumer: consumer.id.clone(),
+            not_after,
+        });
+        // A token listed several times is valid for the longest of them.
+        entry.not_after = match (entry.not_after, not_after) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            _ => None,
+        };
+    }
+    tokens
+}
+
+/// Adds the tokens of a port. Tokens already used by another port are kept by it and ignored
+/// here, so a port can't take over the tokens of another one.
+fn insert_tokens(
+    tokens: &mut HashMap<String, ConsumerToken>,
+    port_tokens: HashMap<String, ConsumerToken>,
+) {
+    for (token, port_token) in port_tokens {
+        match tokens.get(&token) {
+            Some(existing) if existing.consumer != port_token.consumer => {
+                error!(
+                    consumer = port_token.consumer,
+                    owner = existing.consumer,
+                    "auth: Token already used by another port, ignoring it."
+                );
+            }
+            _ => {
+                tokens.insert(token, port_token);
+            }
+        }
+    }
+}
+
+/// Adds or updates a port. The connections of a port already known are carried over, since
+/// they stay open across updates. Returns whether the limits of the port must be reset, which
+/// is only needed for a new port or a tier change, not when only its tokens change.
+fn update_consumer(consumers: &mut HashMap<String, Consumer>, mut consumer: Consumer) -> bool {
+    let tier_changed = match consumers.get(&consumer.id) {
+        Some(existing) => {
+            consumer.active_connections = existing.active_connections;
+            existing.tier != consumer.tier
+        }
+        None => true,
+    };
+    consumers.insert(consumer.id.clone(), consumer);
+    tier_changed
+}
 
 #[instrument("auth background service", skip_all)]
 pub fn start(state: Arc<State>) {
@@ -29,30 +98,49 @@ pub fn start(state: Arc<State>) {
                 // Stream restart, also run on startup.
                 Ok(Some(Event::Restarted(crds))) => {
                     info!("auth: Watcher restarted, reseting consumers");
-                    let consumers: HashMap<String, Consumer> = crds
-                        .iter()
-                        .map(|crd| {
-                            let consumer = Consumer::from(crd);
-                            (consumer.key.clone(), consumer)
-                        })
-                        .collect();
-                    *state.consumers.write().await = consumers;
+                    let mut previous = state.consumers.write().await;
+                    let mut consumers = HashMap::new();
+                    let mut tokens = HashMap::new();
+                    for crd in crds.iter().filter(|crd| crd.status.is_some()) {
+                        let mut consumer = Consumer::from(crd);
+                        if let Some(existing) = previous.get(&consumer.id) {
+                            consumer.active_connections = existing.active_connections;
+                        }
+                        insert_tokens(&mut tokens, consumer_tokens(crd, &consumer));
+                        consumers.insert(consumer.id.clone(), consumer);
+                    }
+                    *previous = consumers;
+                    drop(previous);
+                    *state.tokens.write().await = tokens;
 
                     // When the watcher is restarted, we reset the limiter because a user
                     // could have changed the tier on the watcher restart.
-                    state.limiter.write().await.clear();
+                    state.limiter.clear().await;
+                    state.bandwidth.clear().await;
                 }
                 // New port created or updated.
                 Ok(Some(Event::Applied(crd))) => match crd.status {
                     Some(_) => {
                         info!("auth: Adding new consumer: {}", crd.name_any());
                         let consumer = Consumer::from(&crd);
-                        state.limiter.write().await.remove(&consumer.key);
-                        state
-                            .consumers
-                            .write()
-                            .await
-                            .insert(consumer.key.clone(), consumer);
+
+                        // Tokens removed from the port are revoked, the tokens of other ports are
+                        // left untouched.
+                        let mut tokens = state.tokens.write().await;
+                        tokens.retain(|_, token| token.consumer != consumer.id);
+                        insert_tokens(&mut tokens, consumer_tokens(&crd, &consumer));
+                        drop(tokens);
+
+                        let mut consumers = state.consumers.write().await;
+                        let tier_changed = update_consumer(&mut consumers, consumer.clone());
+                        drop(consumers);
+
+                        // The status is patched on every token rotation, which must not reset
+                        // the rates of the port.
+                        if tier_changed {
+                            state.limiter.remove(&consumer).await;
+                            state.bandwidth.remove(&consumer).await;
+                        }
                     }
                     None => {
                         // New ports are created without status. When the status is added, a new
@@ -67,8 +155,14 @@ pub fn start(state: Arc<State>) {
                         crd.name_any()
                     );
                     let consumer = Consumer::from(&crd);
-                    state.consumers.write().await.remove(&consumer.key);
-                    state.limiter.write().await.remove(&consumer.key);
+                    state.consumers.write().await.remove(&consumer.id);
+                    state
+                        .tokens
+                        .write()
+                        .await
+                        .retain(|_, token| token.consumer != consumer.id);
+                    state.limiter.remove(&consumer).await;
+                    state.bandwidth.remove(&consumer).await;
                 }
                 // Empty response from stream. Should never happen.
                 Ok(None) => {
@@ -84,3 +178,59 @@ pub fn start(state: Arc<State>) {
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn port_tokens(consumer: &str, tokens: &[&str]) -> HashMap<String, ConsumerToken> {
+        tokens
+            .iter()
+            .map(|token| {
+                let token_value = ConsumerToken {
+                    consumer: consumer.into(),
+                    not_after: None,
+                };
+                (token.to_string(), token_value)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn keeps_tokens_of_other_ports() {
+        let mut tokens = HashMap::new();
+        insert_tokens(&mut tokens, port_tokens("a", &["t1", "t2"]));
+        insert_tokens(&mut tokens, port_tokens("b", &["t2", "t3"]));
+
+        assert_eq!(tokens["t1"].consumer, "a");
+        assert_eq!(tokens["t2"].consumer, "a");
+        assert_eq!(tokens["t3"].consumer, "b");
+
+        // A port applied again replaces its own tokens only.
+        tokens.retain(|_, token| token.consumer != "a");
+        insert_tokens(&mut tokens, port_tokens("a", &["t1"]));
+        assert_eq!(tokens["t1"].consumer, "a");
+        assert!(!tokens.contains_key("t2"));
+        assert_eq!(tokens["t3"].consumer, "b");
+    }
+
+    #[test]
+    fn keeps_connections_of_updated_ports() {
+        let consumer = |tier: &str| Consumer {
+            id: "ns.port".into(),
+            tier: tier.into(),
+            ..Default::default()
+        };
+
+        let mut consumers = HashMap::new();
+        assert!(update_consumer(&mut consumers, consumer("0")));
+        consumers.get_mut("ns.port").unwrap().active_connections = 2;
+
+        assert!(!update_consumer(&mut consumers, consumer("0")));
+        assert_eq!(consumers["ns.port"].active_connections, 2);
+
+        assert!(update_consumer(&mut consumers, consumer("1")));
+        assert_eq!(consumers["ns.port"].active_connections, 2);
+        assert_eq!(consumers["ns.port"].tier, "1");
+    }
+}
@@ -0,0 +1,163 @@
+use futures_util::future::join_all;
+use leaky_bucket::RateLimiter;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::RwLock;
+
+use crate::limiter::{consumer_tier, LimiterError};
+use crate::tiers::{Tier, TierBytes};
+use crate::{Consumer, State};
+
+/// Bytes used in the current window of a quota. Windows are aligned to the unix epoch.
+#[derive(Debug, Default)]
+struct QuotaWindow {
+    window: u64,
+    used: usize,
+}
+
+/// Payload bytes of websocket messages and http bodies, in both directions. Rates throttle
+/// the traffic and quotas reject requests once exhausted.
+#[derive(Default)]
+pub struct Bandwidth {
+    buckets: RwLock<HashMap<String, Arc<Vec<RateLimiter>>>>,
+    // Quotas are keyed by consumer and quota, so their usage survives tier reloads.
+    quotas: Mutex<HashMap<String, QuotaWindow>>,
+}
+impl Bandwidth {
+    async fn buckets(&self, consumer: &Consumer, tier: &Tier) -> Arc<Vec<RateLimiter>> {
+        if let Some(buckets) = self.buckets.read().await.get(&consumer.id) {
+            return buckets.clone();
+        }
+
+        let buckets = tier
+            .bandwidth
+            .iter()
+            .map(|rate| {
+                RateLimiter::builder()
+                    .initial(rate.limit)
+                    .interval(rate.interval)
+                    .refill(rate.limit)
+                    .max(rate.limit)
+                    .build()
+            })
+            .collect();
+
+        self.buckets
+            .write()
+            .await
+            .entry(consumer.id.clone())
+            .or_insert(Arc::new(buckets))
+            .clone()
+    }
+
+    /// Charges the bytes to the quotas, unless `check` is set and a quota is already exhausted.
+    /// A message is let through while there is quota left, so the last one can overshoot it.
+    fn charge_quotas(
+        &self,
+        consumer: &Consumer,
+        quotas: &[TierBytes],
+        bytes: usize,
+        check: bool,
+    ) -> Result<(), LimiterError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        let windows: Vec<_> = quotas
+            .iter()
+            .map(|quota| {
+                let interval = (quota.interval.as_millis() as u64).max(1);
+                let window = now / interval;
+                let key = format!("{}:{}-{interval}", consumer.id, quota.limit);
+                let reset = Duration::from_millis((window + 1) * interval - now);
+                (key, window, quota.limit, reset)
+            })
+            .collect();
+
+        let mut current = self.quotas.lock().unwrap();
+        if check {
+            let exhausted = windows
+                .iter()
+                .filter(|(key, window, limit, _)| {
+                    current
+                        .get(key)
+                        .is_some_and(|c| c.window == *window && c.used >= *limit)
+                })
+                .map(|(.., reset)| *reset)
+                .max();
+            if let Some(retry_after) = exhausted {
+                return Err(LimiterError::ByteQuotaExceeded { retry_after });
+            }
+        }
+
+        for (key, window, ..) in windows {
+            let current = current.entry(key).or_default();
+            if current.window != window {
+                *current = QuotaWindow { window, used: 0 };
+            }
+            current.used = current.used.saturating_add(bytes);
+        }
+        Ok(())
+    }
+
+    /// Forgets the rates of the consumer, e.g. when its port is updated.
+    pub async fn remove(&self, consumer: &Consumer) {
+        self.buckets.write().await.remove(&consumer.id);
+    }
+
+    /// Forgets the rates of every consumer and the quota windows already over.
+    pub async fn clear(&self) {
+        self.buckets.write().await.clear();
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        self.quotas.lock().unwrap().retain(|key, current| {
+            let interval = key
+                .rsplit_once('-')
+                .and_then(|(_, interval)| interval.parse::<u64>().ok())
+                .unwrap_or(1);
+            current.window == now / interval
+        });
+    }
+}
+
+async fn charge(
+    state: &State,
+    consumer: &Consumer,
+    bytes: usize,
+    check: bool,
+) -> Result<(), LimiterError> {
+    let tier = consumer_tier(state, consumer).await?;
+    if bytes == 0 || (tier.bandwidth.is_empty() && tier.byte_quotas.is_empty()) {
+        return Ok(());
+    }
+
+    state
+        .bandwidth
+        .charge_quotas(consumer, &tier.byte_quotas, bytes, check)?;
+
+    let buckets = state.bandwidth.buckets(consumer, &tier).await;
+    join_all(buckets.iter().map(|bucket| bucket.acquire(bytes))).await;
+    Ok(())
+}
+
+/// Charges the bytes of a request, waiting while a rate is exhausted. Requests are rejected
+/// once a quota is exhausted.
+pub async fn request_bandwidth(
+    state: &State,
+    consumer: &Consumer,
+    bytes: usize,
+) -> Result<(), LimiterError> {
+    charge(state, consumer, bytes, true).await
+}
+
+/// Charges the bytes of a response, waiting while a rate is exhausted. Responses are always
+/// delivered, an exhausted quota rejects the next request instead.
+pub async fn response_bandwidth(state: &State, consumer: &Consumer, bytes: usize) {
+    // A deleted port or an invalid tier is handled by the limiter on the next request.
+    let _ = charge(state, consumer, bytes, false).await;
+}
@@ -0,0 +1,303 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{oneshot, RwLock};
+
+use crate::jsonrpc::{Envelope, RpcRequest};
+
+const MAX_ENTRIES: usize = 10_000;
+
+pub const CACHE_HIT: &str = "hit";
+pub const CACHE_MISS: &str = "miss";
+pub const CACHE_COALESCED: &str = "coalesced";
+
+type InFlight = Arc<Mutex<HashMap<CacheKey, Vec<oneshot::Sender<Value>>>>>;
+
+/// Chain event that makes a cached answer stale before its ttl.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Invalidation {
+    Never,
+    Epoch,
+    Tip,
+}
+impl Invalidation {
+    fn from_method(method: &str) -> Self {
+        match method {
+            "queryNetwork/genesisConfiguration" | "queryNetwork/startTime" => Invalidation::Never,
+            "queryLedgerState/protocolParameters"
+            | "queryLedgerState/eraSummaries"
+            | "queryLedgerState/epoch"
+            | "queryLedgerState/stakePools"
+            | "queryLedgerState/constitution" => Invalidation::Epoch,
+            // Anything else may change on every block.
+            _ => Invalidation::Tip,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChainPosition {
+    pub slot: Option<u64>,
+    pub epoch: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey(String);
+
+#[derive(Debug, Clone)]
+struct CacheEntry {
+    response: Value,
+    expires_at: Instant,
+    invalidation: Invalidation,
+    position: ChainPosition,
+}
+impl CacheEntry {
+    fn is_fresh(&self, position: &ChainPosition) -> bool {
+        if self.expires_at <= Instant::now() {
+            return false;
+        }
+
+        match self.invalidation {
+            Invalidation::Never => true,
+            Invalidation::Epoch => self.position.epoch == position.epoch,
+            Invalidation::Tip => self.position == *position,
+        }
+    }
+}
+
+/// Identical requests in flight are sent upstream only once. The leader forwards the request
+/// and the followers wait for its response.
+#[derive(Debug)]
+pub enum Flight {
+    Leader(FlightGuard),
+    Follower(oneshot::Receiver<Value>),
+}
+
+/// Held by the leader while the request is in flight. When dropped without a response, the
+/// followers are released to send the request by themselves.
+#[derive(Debug)]
+pub struct FlightGuard {
+    key: Option<CacheKey>,
+    in_flight: InFlight,
+}
+impl FlightGuard {
+    /// Sends the response to the followers. It must be verified to answer the request of the
+    /// leader, since the followers forward it to their clients.
+    pub fn complete(mut self, response: &Value) {
+        let Some(key) = self.key.take() else {
+            return;
+        };
+
+        let waiters = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .remove(&key)
+            .unwrap_or_default();
+        for waiter in waiters {
+            let _ = waiter.send(response.clone());
+        }
+    }
+}
+impl Drop for FlightGuard {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.in_flight.lock().unwrap().remove(&key);
+        }
+    }
+}
+
+/// Cache of idempotent Ogmios queries shared by every consumer of a network and version.
+#[derive(Debug, Default)]
+pub struct Cache {
+    ttls: HashMap<String, Duration>,
+    entries: RwLock<HashMap<CacheKey, CacheEntry>>,
+    positions: RwLock<HashMap<String, ChainPosition>>,
+    in_flight: InFlight,
+}
+impl Cache {
+    pub fn new(ttls: HashMap<String, Duration>) -> Self {
+        Self {
+            ttls,
+            ..Default::default()
+        }
+    }
+
+    /// Key of the request when its method is cacheable. Only JSON-RPC requests are cached,
+    /// since the cached methods are the Ogmios v6 ones.
+    pub fn key(&self, network: &str, version: &str, request: &RpcRequest) -> Option<CacheKey> {
+        if request.envelope != Envelope::JsonRpc || !self.ttls.contains_key(&request.method) {
+            return None;
+        }
+
+        let params = request
+            .params
+            .as_ref()
+            .map(|params| params.to_string())
+            .unwrap_or_default();
+
+        Some(CacheKey(format!(
+            "{network}-{version}:{}:{params}",
+            request.method
+        )))
+    }
+
+    pub async fn get(&self, key: &CacheKey, request: &RpcRequest) -> Option<Value> {
+        let position = self.position(key).await;
+        let entries = self.entries.read().await;
+        let entry = entries.get(key)?;
+        if !entry.is_fresh(&position) {
+            return None;
+        }
+
+        Some(request.with_id(entry.response.clone()))
+    }
+
+    pub fn join(&self, key: &CacheKey) -> Flight {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        match in_flight.get_mut(key) {
+            Some(waiters) => {
+                let (tx, rx) = oneshot::channel();
+                waiters.push(tx);
+                Flight::Follower(rx)
+            }
+            None => {
+                in_flight.insert(key.clone(), Vec::new());
+                Flight::Leader(FlightGuard {
+                    key: Some(key.clone()),
+                    in_flight: self.in_flight.clone(),
+                })
+            }
+        }
+    }
+
+    /// Stores the response of a request sent at the given chain position, errors are never
+    /// cached. A response computed before the tip or the epoch moved is already stale, so it is
+    /// dropped instead of being stored under the new position.
+    pub async fn insert(
+        &self,
+        key: CacheKey,
+        method: &str,
+        response: Value,
+        position: ChainPosition,
+    ) {
+        if response.get("error").is_some() || response.get("result").is_none() {
+            return;
+        }
+        let Some(ttl) = self.ttls.get(method) else {
+            return;
+        };
+
+        let entry = CacheEntry {
+            response,
+            expires_at: Instant::now() + *ttl,
+            invalidation: Invalidation::from_method(method),
+            position,
+        };
+        if !entry.is_fresh(&self.position(&key).await) {
+            return;
+        }
+
+        let mut entries = self.entries.write().await;
+        if entries.len() >= MAX_ENTRIES {
+            let now = Instant::now();
+            entries.retain(|_, entry| entry.expires_at > now);
+        }
+        if entries.len() < MAX_ENTRIES {
+            entries.insert(key, entry);
+        }
+    }
+
+    /// Records the chain position of a network and version, entries depending on the tip or
+    /// the epoch become stale when it moves.
+    pub async fn set_position(&self, network: &str, version: &str, position: ChainPosition) {
+        self.positions
+            .write()
+            .await
+            .insert(format!("{network}-{version}"), position);
+    }
+
+    /// Chain position of the network and version of the key, to be recorded when a request is
+    /// sent and given back to `insert`.
+    pub async fn position(&self, key: &CacheKey) -> ChainPosition {
+        let pool_key = key.0.split(':').next().unwrap_or_default();
+        self.positions
+            .read()
+            .await
+            .get(pool_key)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const TIP: &str = "queryNetwork/tip";
+    const PARAMETERS: &str = "queryLedgerState/protocolParameters";
+
+    fn cache() -> Cache {
+        let ttl = Duration::from_secs(60);
+        Cache::new(HashMap::from([(TIP.into(), ttl), (PARAMETERS.into(), ttl)]))
+    }
+
+    fn request(method: &str) -> RpcRequest {
+        let request = json!({ "jsonrpc": "2.0", "method": method, "id": 1 });
+        RpcRequest::parse(request.to_string().as_bytes()).unwrap()
+    }
+
+    fn position(slot: u64, epoch: u64) -> ChainPosition {
+        ChainPosition {
+            slot: Some(slot),
+            epoch: Some(epoch),
+        }
+    }
+
+    #[tokio::test]
+    async fn drops_responses_sent_before_the_position_moved() {
+        let cache = cache();
+        let response = json!({ "jsonrpc": "2.0", "method": TIP, "result": {}, "id": 1 });
+        let tip = request(TIP);
+        let key = cache.key("mainnet", "6", &tip).unwrap();
+        cache.set_position("mainnet", "6", position(10, 1)).await;
+
+        // The tip moved while the request was in flight.
+        let sent_at = cache.position(&key).await;
+        cache.set_position("mainnet", "6", position(11, 1)).await;
+        cache
+            .insert(key.clone(), TIP, response.clone(), sent_at)
+            .await;
+        assert!(cache.get(&key, &tip).await.is_none());
+
+        let sent_at = cache.position(&key).await;
+        cache.insert(key.clone(), TIP, response, sent_at).await;
+        assert!(cache.get(&key, &tip).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn keeps_epoch_responses_while_only_the_tip_moved() {
+        let cache = cache();
+        let response = json!({ "jsonrpc": "2.0", "method": PARAMETERS, "result": {}, "id": 1 });
+        let parameters = request(PARAMETERS);
+        let key = cache.key("mainnet", "6", &parameters).unwrap();
+        cache.set_position("mainnet", "6", position(10, 1)).await;
+
+        let sent_at = cache.position(&key).await;
+        cache.set_position("mainnet", "6", position(11, 1)).await;
+        cache
+            .insert(key.clone(), PARAMETERS, response.clone(), sent_at)
+            .await;
+        assert!(cache.get(&key, &parameters).await.is_some());
+
+        // A new epoch makes it stale.
+        cache.set_position("mainnet", "6", position(12, 2)).await;
+        assert!(cache.get(&key, &parameters).await.is_none());
+        cache
+            .insert(key.clone(), PARAMETERS, response, sent_at)
+            .await;
+        assert!(cache.get(&key, &parameters).await.is_none());
+    }
+}
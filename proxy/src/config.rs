@@ -1,22 +1,59 @@
-use std::{env, path::PathBuf, time::Duration};
+use std::{collections::HashMap, env, path::PathBuf, time::Duration};
+
+use crate::proxy::ListenerMode;
+use crate::upstream::Strategy;
+
+const DEFAULT_PROXY_CACHE_TTL: &str = "queryLedgerState/protocolParameters=300,queryNetwork/genesisConfiguration=3600,queryLedgerState/eraSummaries=300,queryNetwork/tip=5";
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub proxy_addr: String,
+    pub proxy_listener_mode: ListenerMode,
+    pub proxy_plaintext_addr: Option<String>,
     pub proxy_namespace: String,
     pub proxy_tiers_path: PathBuf,
     pub proxy_tiers_poll_interval: Duration,
     pub prometheus_addr: String,
     pub ogmios_port: u16,
     pub ogmios_dns: String,
-    pub ssl_crt_path: PathBuf,
-    pub ssl_key_path: PathBuf,
+    pub ogmios_health_poll_interval: Duration,
+    pub ogmios_health_timeout: Duration,
+    pub ogmios_min_network_synchronization: f64,
+    pub ogmios_upstreams: HashMap<String, Vec<String>>,
+    pub ogmios_upstream_strategy: Strategy,
+    pub ogmios_http_connect_timeout: Duration,
+    pub ogmios_http_pool_idle_timeout: Duration,
+    pub ogmios_http_pool_max_idle: usize,
+    pub proxy_cache_ttl: HashMap<String, Duration>,
+    pub proxy_v5_translation: bool,
+    pub proxy_redis_url: Option<String>,
+    pub proxy_redis_timeout: Duration,
+    pub proxy_connection_lease_ttl: Duration,
+    pub proxy_quota_snapshot_path: Option<PathBuf>,
+    pub proxy_quota_snapshot_interval: Duration,
+    pub proxy_ws_ping_interval: Option<Duration>,
+    pub proxy_ws_pong_timeout: Duration,
+    pub ogmios_ws_ping_interval: Option<Duration>,
+    pub ogmios_ws_pong_timeout: Duration,
+    pub proxy_prestop_delay: Duration,
+    pub proxy_drain_timeout: Duration,
+    pub ssl_crt_path: Option<PathBuf>,
+    pub ssl_key_path: Option<PathBuf>,
+    pub ssl_certs_path: Option<PathBuf>,
+    pub ssl_poll_interval: Duration,
 }
 
 impl Config {
     pub fn new() -> Self {
         Self {
             proxy_addr: env::var("PROXY_ADDR").expect("PROXY_ADDR must be set"),
+            proxy_listener_mode: env::var("PROXY_LISTENER_MODE")
+                .map(|v| {
+                    v.parse()
+                        .expect("PROXY_LISTENER_MODE must be tls, plaintext or dual")
+                })
+                .unwrap_or(ListenerMode::Tls),
+            proxy_plaintext_addr: env::var("PROXY_PLAINTEXT_ADDR").ok(),
             proxy_namespace: env::var("PROXY_NAMESPACE").unwrap_or("ftr-ogmios-v1".into()),
             proxy_tiers_path: env::var("PROXY_TIERS_PATH")
                 .map(|v| v.into())
@@ -30,17 +67,189 @@ impl Config {
                 })
                 .unwrap_or(Duration::from_secs(2)),
             prometheus_addr: env::var("PROMETHEUS_ADDR").expect("PROMETHEUS_ADDR must be set"),
-            ssl_crt_path: env::var("SSL_CRT_PATH")
-                .map(|e| e.into())
-                .expect("SSL_CRT_PATH must be set"),
-            ssl_key_path: env::var("SSL_KEY_PATH")
-                .map(|e| e.into())
-                .expect("SSL_KEY_PATH must be set"),
+            ssl_crt_path: env::var("SSL_CRT_PATH").ok().map(|e| e.into()),
+            ssl_key_path: env::var("SSL_KEY_PATH").ok().map(|e| e.into()),
+            ssl_certs_path: env::var("SSL_CERTS_PATH").ok().map(|e| e.into()),
+            ssl_poll_interval: env::var("SSL_POLL_INTERVAL")
+                .map(|v| {
+                    Duration::from_secs(
+                        v.parse::<u64>()
+                            .expect("SSL_POLL_INTERVAL must be a number in seconds. eg: 10"),
+                    )
+                })
+                .unwrap_or(Duration::from_secs(10)),
             ogmios_port: env::var("OGMIOS_PORT")
                 .expect("OGMIOS_PORT must be set")
                 .parse()
                 .expect("OGMIOS_PORT must a number"),
             ogmios_dns: env::var("OGMIOS_DNS").expect("OGMIOS_DNS must be set"),
+            ogmios_health_poll_interval: env::var("OGMIOS_HEALTH_POLL_INTERVAL")
+                .map(|v| {
+                    Duration::from_secs(
+                        v.parse::<u64>().expect(
+                            "OGMIOS_HEALTH_POLL_INTERVAL must be a number in seconds. eg: 10",
+                        ),
+                    )
+                })
+                .unwrap_or(Duration::from_secs(10)),
+            ogmios_health_timeout: env::var("OGMIOS_HEALTH_TIMEOUT")
+                .map(|v| {
+                    Duration::from_secs(
+                        v.parse::<u64>()
+                            .expect("OGMIOS_HEALTH_TIMEOUT must be a number in seconds. eg: 5"),
+                    )
+                })
+                .unwrap_or(Duration::from_secs(5)),
+            ogmios_min_network_synchronization: env::var("OGMIOS_MIN_NETWORK_SYNCHRONIZATION")
+                .map(|v| {
+                    v.parse::<f64>().expect(
+                        "OGMIOS_MIN_NETWORK_SYNCHRONIZATION must be a number between 0 and 1",
+                    )
+                })
+                .unwrap_or(0.99999),
+            ogmios_upstreams: env::var("OGMIOS_UPSTREAMS")
+                .map(|v| {
+                    v.split(',')
+                        .map(|pair| {
+                            let (key, addrs) = pair.split_once('=').expect(
+                                "OGMIOS_UPSTREAMS must be NETWORK-VERSION=ADDR;ADDR,NETWORK-VERSION=ADDR",
+                            );
+                            let addrs = addrs.split(';').map(|addr| addr.trim().into()).collect();
+                            (key.trim().into(), addrs)
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+            ogmios_upstream_strategy: env::var("OGMIOS_UPSTREAM_STRATEGY")
+                .map(|v| {
+                    v.parse().expect(
+                        "OGMIOS_UPSTREAM_STRATEGY must be round-robin or least-connections",
+                    )
+                })
+                .unwrap_or(Strategy::RoundRobin),
+            ogmios_http_connect_timeout: env::var("OGMIOS_HTTP_CONNECT_TIMEOUT")
+                .map(|v| {
+                    Duration::from_secs(
+                        v.parse::<u64>().expect(
+                            "OGMIOS_HTTP_CONNECT_TIMEOUT must be a number in seconds. eg: 5",
+                        ),
+                    )
+                })
+                .unwrap_or(Duration::from_secs(5)),
+            ogmios_http_pool_idle_timeout: env::var("OGMIOS_HTTP_POOL_IDLE_TIMEOUT")
+                .map(|v| {
+                    Duration::from_secs(
+                        v.parse::<u64>().expect(
+                            "OGMIOS_HTTP_POOL_IDLE_TIMEOUT must be a number in seconds. eg: 90",
+                        ),
+                    )
+                })
+                .unwrap_or(Duration::from_secs(90)),
+            ogmios_http_pool_max_idle: env::var("OGMIOS_HTTP_POOL_MAX_IDLE")
+                .map(|v| {
+                    v.parse::<usize>()
+                        .expect("OGMIOS_HTTP_POOL_MAX_IDLE must be a number. eg: 32")
+                })
+                .unwrap_or(32),
+            proxy_cache_ttl: env::var("PROXY_CACHE_TTL")
+                .unwrap_or(DEFAULT_PROXY_CACHE_TTL.into())
+                .split(',')
+                .filter(|pair| !pair.is_empty())
+                .map(|pair| {
+                    let (method, ttl) = pair
+                        .split_once('=')
+                        .expect("PROXY_CACHE_TTL must be METHOD=SECONDS,METHOD=SECONDS");
+                    let ttl = ttl
+                        .trim()
+                        .parse::<u64>()
+                        .expect("PROXY_CACHE_TTL must be METHOD=SECONDS,METHOD=SECONDS");
+                    (method.trim().into(), Duration::from_secs(ttl))
+                })
+                .collect(),
+            proxy_v5_translation: env::var("PROXY_V5_TRANSLATION")
+                .map(|v| {
+                    v.parse()
+                        .expect("PROXY_V5_TRANSLATION must be true or false")
+                })
+                .unwrap_or_default(),
+            proxy_redis_url: env::var("PROXY_REDIS_URL").ok(),
+            proxy_redis_timeout: env::var("PROXY_REDIS_TIMEOUT")
+                .map(|v| {
+                    Duration::from_millis(
+                        v.parse::<u64>()
+                            .expect("PROXY_REDIS_TIMEOUT must be a number in milliseconds. eg: 500"),
+                    )
+                })
+                .unwrap_or(Duration::from_millis(500)),
+            proxy_connection_lease_ttl: env::var("PROXY_CONNECTION_LEASE_TTL")
+                .map(|v| {
+                    Duration::from_secs(
+                        v.parse::<u64>().expect(
+                            "PROXY_CONNECTION_LEASE_TTL must be a number in seconds. eg: 30",
+                        ),
+                    )
+                })
+                .unwrap_or(Duration::from_secs(30)),
+            proxy_quota_snapshot_path: env::var("PROXY_QUOTA_SNAPSHOT_PATH")
+                .map(|v| v.into())
+                .ok(),
+            proxy_quota_snapshot_interval: env::var("PROXY_QUOTA_SNAPSHOT_INTERVAL")
+                .map(|v| {
+                    Duration::from_secs(v.parse::<u64>().expect(
+                        "PROXY_QUOTA_SNAPSHOT_INTERVAL must be a number in seconds. eg: 10",
+                    ))
+                })
+                .unwrap_or(Duration::from_secs(10)),
+            proxy_ws_ping_interval: env::var("PROXY_WS_PING_INTERVAL")
+                .map(|v| {
+                    Duration::from_secs(
+                        v.parse::<u64>()
+                            .expect("PROXY_WS_PING_INTERVAL must be a number in seconds. eg: 30"),
+                    )
+                })
+                .map(|interval| Some(interval).filter(|i| !i.is_zero()))
+                .unwrap_or(Some(Duration::from_secs(30))),
+            proxy_ws_pong_timeout: env::var("PROXY_WS_PONG_TIMEOUT")
+                .map(|v| {
+                    Duration::from_secs(
+                        v.parse::<u64>()
+                            .expect("PROXY_WS_PONG_TIMEOUT must be a number in seconds. eg: 10"),
+                    )
+                })
+                .unwrap_or(Duration::from_secs(10)),
+            ogmios_ws_ping_interval: env::var("OGMIOS_WS_PING_INTERVAL")
+                .map(|v| {
+                    Duration::from_secs(
+                        v.parse::<u64>()
+                            .expect("OGMIOS_WS_PING_INTERVAL must be a number in seconds. eg: 30"),
+                    )
+                })
+                .map(|interval| Some(interval).filter(|i| !i.is_zero()))
+                .unwrap_or(Some(Duration::from_secs(30))),
+            ogmios_ws_pong_timeout: env::var("OGMIOS_WS_PONG_TIMEOUT")
+                .map(|v| {
+                    Duration::from_secs(
+                        v.parse::<u64>()
+                            .expect("OGMIOS_WS_PONG_TIMEOUT must be a number in seconds. eg: 10"),
+                    )
+                })
+                .unwrap_or(Duration::from_secs(10)),
+            proxy_prestop_delay: env::var("PROXY_PRESTOP_DELAY")
+                .map(|v| {
+                    Duration::from_secs(
+                        v.parse::<u64>()
+                            .expect("PROXY_PRESTOP_DELAY must be a number in seconds. eg: 10"),
+                    )
+                })
+                .unwrap_or(Duration::from_secs(10)),
+            proxy_drain_timeout: env::var("PROXY_DRAIN_TIMEOUT")
+                .map(|v| {
+                    Duration::from_secs(
+                        v.parse::<u64>()
+                            .expect("PROXY_DRAIN_TIMEOUT must be a number in seconds. eg: 60"),
+                    )
+                })
+                .unwrap_or(Duration::from_secs(60)),
         }
     }
 }
@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, instrument};
+
+use crate::limiter::REDIS_PREFIX;
+use crate::redis::{Cmd, RedisClient, RedisError};
+use crate::{Consumer, State};
+
+/// Websocket sessions of every proxy replica, kept in Redis as a sorted set of leases per
+/// consumer scored by their expiry. Each replica refreshes the leases of its own sessions, so
+/// the leases of a crashed replica expire and are cleaned up by the next connection.
+pub struct ConnectionLeases {
+    client: Arc<RedisClient>,
+    ttl: Duration,
+    replica: String,
+    counter: AtomicU64,
+    /// Leases of the sessions of this replica, by id, with the key of their set.
+    local: Mutex<HashMap<String, String>>,
+}
+impl ConnectionLeases {
+    pub fn new(client: Arc<RedisClient>, ttl: Duration) -> Self {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let hostname = std::env::var("HOSTNAME").unwrap_or("proxy".into());
+
+        Self {
+            client,
+            ttl,
+            replica: format!("{hostname}-{started}"),
+            counter: Default::default(),
+            local: Default::default(),
+        }
+    }
+
+    /// Takes one of the `max_connections` slots of the consumer across the fleet. Returns
+    /// `None` when every slot is taken.
+    pub async fn acquire(
+        self: &Arc<Self>,
+        consumer: &Consumer,
+        max_connections: usize,
+    ) -> Result<Option<ConnectionLease>, RedisError> {
+        let key = format!("{REDIS_PREFIX}:connections:{consumer}");
+        let id = format!(
+            "{}-{}",
+            self.replica,
+            self.counter.fetch_add(1, Ordering::Relaxed)
+        );
+        let now = now_millis();
+        let ttl = self.ttl.as_millis();
+
+        // The lease is added before counting, so two replicas racing for the last slot can both
+        // be refused but never both accepted.
+        let replies = self
+            .client
+            .pipeline(&[
+                Cmd::new("ZREMRANGEBYSCORE").arg(&key).arg("-inf").arg(now),
+                Cmd::new("ZADD").arg(&key).arg(now + ttl).arg(&id),
+                Cmd::new("ZCARD").arg(&key),
+                Cmd::new("PEXPIRE").arg(&key).arg(ttl * 2),
+            ])
+            .await?;
+        let count = replies[2]
+            .as_integer()
+            .ok_or(RedisError::Protocol("ZCARD must reply an integer".into()))?;
+
+        if count > max_connections as i64 {
+            self.client
+                .pipeline(&[Cmd::new("ZREM").arg(&key).arg(&id)])
+                .await?;
+            return Ok(None);
+        }
+
+        self.local.lock().unwrap().insert(id.clone(), key.clone());
+        Ok(Some(ConnectionLease {
+            leases: self.clone(),
+            key,
+            id,
+        }))
+    }
+
+    async fn refresh(&self) -> Result<(), RedisError> {
+        let expiry = now_millis() + self.ttl.as_millis();
+        let cmds: Vec<Cmd> = self
+            .local
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(|(id, key)| {
+                [
+                    Cmd::new("ZADD").arg(key).arg("XX").arg(expiry).arg(id),
+                    Cmd::new("PEXPIRE").arg(key).arg(self.ttl.as_millis() * 2),
+                ]
+            })
+            .collect();
+
+        if !cmds.is_empty() {
+            self.client.pipeline(&cmds).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Slot taken by a websocket session, released when dropped.
+pub struct ConnectionLease {
+    leases: Arc<ConnectionLeases>,
+    key: String,
+    id: String,
+}
+impl Drop for ConnectionLease {
+    fn drop(&mut self) {
+        self.leases.local.lock().unwrap().remove(&self.id);
+
+        let client = self.leases.client.clone();
+        let cmd = Cmd::new("ZREM").arg(&self.key).arg(&self.id);
+        tokio::spawn(async move {
+            if let Err(err) = client.pipeline(&[cmd]).await {
+                error!(error = err.to_string(), "fail to release connection lease");
+            }
+        });
+    }
+}
+
+fn now_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+#[instrument("connections background service", skip_all)]
+pub fn start(state: Arc<State>) {
+    let Some(leases) = state.connection_leases.clone() else {
+        return;
+    };
+
+    tokio::spawn(async move {
+        info!("connections: refreshing connection leases");
+
+        loop {
+            tokio::time::sleep(leases.ttl / 3).await;
+
+            if let Err(err) = leases.refresh().await {
+                error!(error = err.to_string(), "fail to refresh connection leases");
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redis::stand_in::{self, Entry};
+
+    fn members(keys: &stand_in::Store) -> Vec<String> {
+        match keys.lock().unwrap().get("ogmios-proxy:connections:ns.port") {
+            Some(Entry::Set(members)) => members.iter().map(|(_, m)| m.clone()).collect(),
+            _ => vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn leases_hold_the_connection_limit() {
+        let (url, keys) = stand_in::start().await;
+        let client = RedisClient::new(&url, Duration::from_secs(1)).unwrap();
+        let leases = Arc::new(ConnectionLeases::new(
+            Arc::new(client),
+            Duration::from_secs(60),
+        ));
+        let consumer = Consumer {
+            namespace: "ns".into(),
+            port_name: "port".into(),
+            ..Default::default()
+        };
+
+        let lease = leases.acquire(&consumer, 1).await.unwrap().unwrap();
+        assert_eq!(members(&keys), [lease.id.as_str()]);
+
+        // The refused lease is removed right away.
+        assert!(leases.acquire(&consumer, 1).await.unwrap().is_none());
+        assert_eq!(members(&keys), [lease.id.as_str()]);
+
+        leases.refresh().await.unwrap();
+        assert_eq!(members(&keys), [lease.id.as_str()]);
+
+        // Leases are released in the background once dropped.
+        drop(lease);
+        for _ in 0..100 {
+            if members(&keys).is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(members(&keys).is_empty());
+        assert!(leases.local.lock().unwrap().is_empty());
+
+        assert!(leases.acquire(&consumer, 1).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn expired_leases_free_their_slot() {
+        let (url, keys) = stand_in::start().await;
+        let client = RedisClient::new(&url, Duration::from_secs(1)).unwrap();
+        let leases = Arc::new(ConnectionLeases::new(
+            Arc::new(client),
+            Duration::from_secs(60),
+        ));
+        let consumer = Consumer {
+            namespace: "ns".into(),
+            port_name: "port".into(),
+            ..Default::default()
+        };
+
+        // Lease of a crashed replica, expired a second ago.
+        let expired = (now_millis() - 1000) as f64;
+        keys.lock().unwrap().insert(
+            "ogmios-proxy:connections:ns.port".into(),
+            Entry::Set(vec![(expired, "crashed-0".into())]),
+        );
+
+        let lease = leases.acquire(&consumer, 1).await.unwrap().unwrap();
+        assert_eq!(members(&keys), [lease.id.as_str()]);
+    }
+}
@@ -0,0 +1,166 @@
+use bytes::Bytes;
+use futures_util::future::join_all;
+use http_body_util::{BodyExt, Empty};
+use hyper::client::conn::http1 as http1_client;
+use hyper::header::HOST;
+use hyper::Request;
+use hyper_util::rt::TokioIo;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tracing::{error, info, instrument, warn};
+
+use crate::cache::ChainPosition;
+use crate::upstream::get_pool;
+use crate::State;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OgmiosHealth {
+    connection_status: Option<String>,
+    network_synchronization: Option<f64>,
+    last_known_tip: Option<OgmiosTip>,
+    current_epoch: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct OgmiosTip {
+    slot: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct InstanceHealth {
+    pub reachable: bool,
+    pub connected: bool,
+    pub network_synchronization: f64,
+    pub last_known_tip_slot: Option<u64>,
+    pub current_epoch: Option<u64>,
+}
+impl InstanceHealth {
+    pub fn is_healthy(&self, min_network_synchronization: f64) -> bool {
+        self.reachable
+            && self.connected
+            && self.network_synchronization >= min_network_synchronization
+    }
+
+    pub fn reason(&self) -> &'static str {
+        if !self.reachable {
+            return "Ogmios instance is unavailable. Try again later.";
+        }
+        if !self.connected {
+            return "Ogmios instance is not connected to the cardano node. Try again later.";
+        }
+        "Ogmios instance is not synchronized with the network. Try again later."
+    }
+}
+impl From<OgmiosHealth> for InstanceHealth {
+    fn from(value: OgmiosHealth) -> Self {
+        Self {
+            reachable: true,
+            connected: value
+                .connection_status
+                .map(|status| status.eq_ignore_ascii_case("connected"))
+                .unwrap_or_default(),
+            network_synchronization: value.network_synchronization.unwrap_or_default(),
+            last_known_tip_slot: value.last_known_tip.and_then(|tip| tip.slot),
+            current_epoch: value.current_epoch,
+        }
+    }
+}
+
+async fn fetch_health(instance: &str) -> Result<OgmiosHealth, Box<dyn Error + Send + Sync>> {
+    let stream = TcpStream::connect(instance).await?;
+    let io = TokioIo::new(stream);
+
+    let (mut sender, conn) = http1_client::handshake(io).await?;
+    tokio::task::spawn(async move {
+        if let Err(err) = conn.await {
+            error!(error = err.to_string(), "health: connection failed");
+        }
+    });
+
+    let req = Request::get("/health")
+        .header(HOST, instance)
+        .body(Empty::<Bytes>::new())?;
+    let res = sender.send_request(req).await?;
+    let status = res.status();
+
+    // Ogmios doesn't answer 200 while the node is not synced or connected, but the body is
+    // the same, so it's parsed regardless of the status code.
+    let body = res.collect().await?.to_bytes();
+    serde_json::from_slice(&body)
+        .map_err(|err| format!("invalid health response, status {status}: {err}").into())
+}
+
+async fn check_instance(state: &State, instance: String) -> (String, InstanceHealth) {
+    let result =
+        tokio::time::timeout(state.config.ogmios_health_timeout, fetch_health(&instance)).await;
+
+    let health = match result {
+        Ok(Ok(health)) => InstanceHealth::from(health),
+        Ok(Err(err)) => {
+            warn!(instance, error = err.to_string(), "health: request failed");
+            InstanceHealth::default()
+        }
+        Err(_) => {
+            warn!(instance, "health: request timed out");
+            InstanceHealth::default()
+        }
+    };
+
+    state.metrics.set_instance_health(
+        &instance,
+        health.is_healthy(state.config.ogmios_min_network_synchronization),
+        health.network_synchronization,
+        health.last_known_tip_slot,
+    );
+
+    (instance, health)
+}
+
+#[instrument("health background service", skip_all)]
+pub fn start(state: Arc<State>) {
+    tokio::spawn(async move {
+        info!("health: polling ogmios instances");
+
+        loop {
+            let networks: HashSet<(String, String)> = state
+                .consumers
+                .read()
+                .await
+                .values()
+                .map(|consumer| {
+                    let version = state.upstream_version(&consumer.version);
+                    (consumer.network.clone(), version.to_string())
+                })
+                .collect();
+
+            let mut instances_health = HashMap::new();
+            for (network, version) in networks {
+                let addrs = get_pool(&state, &network, &version).await.addrs();
+                let checks = addrs
+                    .into_iter()
+                    .map(|instance| check_instance(&state, instance));
+                let results = join_all(checks).await;
+
+                // The most advanced upstream defines the chain position used by the cache.
+                let position = ChainPosition {
+                    slot: results
+                        .iter()
+                        .filter_map(|(_, h)| h.last_known_tip_slot)
+                        .max(),
+                    epoch: results.iter().filter_map(|(_, h)| h.current_epoch).max(),
+                };
+                state.cache.set_position(&network, &version, position).await;
+
+                instances_health.extend(results);
+            }
+
+            *state.instances_health.write().await = instances_health;
+
+            tokio::time::sleep(state.config.ogmios_health_poll_interval).await;
+        }
+    });
+}
@@ -0,0 +1,246 @@
+use serde::de::IgnoredAny;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio_tungstenite::tungstenite::Message;
+
+pub const UNKNOWN_METHOD: &str = "unknown";
+
+// Standard JSON-RPC error for messages that aren't a valid request object.
+pub const INVALID_REQUEST: i64 = -32600;
+pub const INVALID_REQUEST_MESSAGE: &str =
+    "Invalid request. Only well-formed requests are forwarded for the current tier.";
+
+// JSON-RPC server error codes reserved for the proxy, Ogmios uses its own range for errors
+// returned by the instance.
+pub const METHOD_NOT_ALLOWED: i64 = -32001;
+pub const METHOD_NOT_ALLOWED_MESSAGE: &str =
+    "Method not allowed for the current tier. Contact support team for more information.";
+pub const METHOD_NOT_SUPPORTED: i64 = -32002;
+pub const RATE_LIMITED: i64 = -32003;
+pub const RATE_LIMITED_MESSAGE: &str =
+    "Rate limit exceeded for the current tier. Retry after the given number of seconds.";
+pub const BYTE_QUOTA_EXCEEDED: i64 = -32004;
+pub const BYTE_QUOTA_EXCEEDED_MESSAGE: &str =
+    "Byte quota exceeded for the current tier. Retry after the given number of seconds.";
+pub const QUOTA_EXCEEDED: i64 = -32005;
+pub const QUOTA_EXCEEDED_MESSAGE: &str =
+    "Request quota exceeded for the current plan. Contact support team for more information.";
+
+/// Error answering a message that isn't a valid request, when the tier restricts methods.
+pub fn invalid_request() -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "error": { "code": INVALID_REQUEST, "message": INVALID_REQUEST_MESSAGE },
+        "id": null,
+    })
+}
+
+/// Envelope used by the Ogmios frame. Ogmios v6 speaks JSON-RPC 2.0 and Ogmios v5 speaks
+/// JSON-WSP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Envelope {
+    JsonRpc,
+    JsonWsp,
+}
+
+// Only the fields needed by the proxy are deserialized, the remaining payload (blocks, utxos,
+// etc) is skipped by serde without allocating.
+#[derive(Debug, Deserialize)]
+struct RawFrame {
+    jsonrpc: Option<IgnoredAny>,
+    method: Option<String>,
+    params: Option<Value>,
+    id: Option<Value>,
+    error: Option<IgnoredAny>,
+
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    methodname: Option<String>,
+    args: Option<Value>,
+    mirror: Option<Value>,
+    reflection: Option<Value>,
+    fault: Option<IgnoredAny>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RpcRequest {
+    pub envelope: Envelope,
+    pub method: String,
+    pub params: Option<Value>,
+    pub id: Option<Value>,
+}
+impl RpcRequest {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let frame: RawFrame = serde_json::from_slice(data).ok()?;
+
+        if frame.jsonrpc.is_some() {
+            return Some(Self {
+                envelope: Envelope::JsonRpc,
+                method: frame.method?,
+                params: frame.params,
+                id: frame.id,
+            });
+        }
+
+        if frame.kind.as_deref() == Some("jsonwsp/request") {
+            return Some(Self {
+                envelope: Envelope::JsonWsp,
+                method: frame.methodname?,
+                params: frame.args,
+                id: frame.mirror,
+            });
+        }
+
+        None
+    }
+
+    pub fn from_message(message: &Message) -> Option<Self> {
+        match message {
+            Message::Text(text) => Self::parse(text.as_bytes()),
+            Message::Binary(data) => Self::parse(data),
+            _ => None,
+        }
+    }
+
+    /// Build an error reply for this request using the same envelope the client used, so the
+    /// client can correlate it through the original `id` (or `mirror` for JSON-WSP).
+    pub fn error(&self, code: i64, message: &str, data: Option<Value>) -> Value {
+        match self.envelope {
+            Envelope::JsonRpc => {
+                let mut error = json!({ "code": code, "message": message });
+                if let Some(data) = data {
+                    error["data"] = data;
+                }
+                json!({
+                    "jsonrpc": "2.0",
+                    "method": self.method,
+                    "error": error,
+                    "id": self.id,
+                })
+            }
+            Envelope::JsonWsp => {
+                let mut fault = json!({ "code": "client", "string": message });
+                if let Some(data) = data {
+                    fault["data"] = data;
+                }
+                json!({
+                    "type": "jsonwsp/fault",
+                    "version": "1.0",
+                    "servicename": "ogmios",
+                    "methodname": self.method,
+                    "fault": fault,
+                    "reflection": self.id,
+                })
+            }
+        }
+    }
+
+    /// Rewrites the `id` of a response to this request, used when the response was produced for
+    /// another client.
+    pub fn with_id(&self, mut response: Value) -> Value {
+        let field = match self.envelope {
+            Envelope::JsonRpc => "id",
+            Envelope::JsonWsp => "reflection",
+        };
+        response[field] = self.id.clone().unwrap_or(Value::Null);
+        response
+    }
+
+    pub fn value(&self) -> Value {
+        match self.envelope {
+            Envelope::JsonRpc => {
+                let mut request = json!({ "jsonrpc": "2.0", "method": self.method });
+                if let Some(params) = &self.params {
+                    request["params"] = params.clone();
+                }
+                if let Some(id) = &self.id {
+                    request["id"] = id.clone();
+                }
+                request
+            }
+            Envelope::JsonWsp => json!({
+                "type": "jsonwsp/request",
+                "version": "1.0",
+                "servicename": "ogmios",
+                "methodname": self.method,
+                "args": self.params,
+                "mirror": self.id,
+            }),
+        }
+    }
+
+    pub fn message(&self) -> Message {
+        Message::Text(self.value().to_string())
+    }
+
+    pub fn error_message(&self, code: i64, message: &str, data: Option<Value>) -> Message {
+        Message::Text(self.error(code, message, data).to_string())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RpcResponse {
+    pub method: Option<String>,
+    pub id: Option<Value>,
+    pub is_error: bool,
+}
+impl RpcResponse {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let frame: RawFrame = serde_json::from_slice(data).ok()?;
+
+        if frame.jsonrpc.is_some() {
+            return Some(Self {
+                method: frame.method,
+                id: frame.id,
+                is_error: frame.error.is_some(),
+            });
+        }
+
+        match frame.kind.as_deref() {
+            Some("jsonwsp/response") | Some("jsonwsp/fault") => Some(Self {
+                method: frame.methodname,
+                id: frame.reflection,
+                is_error: frame.fault.is_some(),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn from_message(message: &Message) -> Option<Self> {
+        match message {
+            Message::Text(text) => Self::parse(text.as_bytes()),
+            Message::Binary(data) => Self::parse(data),
+            _ => None,
+        }
+    }
+
+    pub fn method(&self) -> &str {
+        self.method.as_deref().unwrap_or(UNKNOWN_METHOD)
+    }
+
+    pub fn status(&self) -> &'static str {
+        match self.is_error {
+            true => "error",
+            false => "success",
+        }
+    }
+}
+
+/// Whether a response is for the method of the request. Ids alone can't tell, clients may reuse
+/// them across methods.
+pub fn answers(request: &RpcRequest, response: &Value) -> bool {
+    response.get("method").and_then(Value::as_str) == Some(request.method.as_str())
+}
+
+/// Key used to correlate a request with its response, since ids can be any json value.
+pub fn id_key(id: &Option<Value>) -> String {
+    id.as_ref().map(|id| id.to_string()).unwrap_or_default()
+}
+
+pub fn message_value(message: &Message) -> Option<Value> {
+    match message {
+        Message::Text(text) => serde_json::from_str(text).ok(),
+        Message::Binary(data) => serde_json::from_slice(data).ok(),
+        _ => None,
+    }
+}
@@ -1,70 +1,566 @@
-use futures_util::future::join_all;
-use leaky_bucket::RateLimiter;
-use std::sync::Arc;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{error::Error, fmt::Display};
+use tokio::sync::RwLock;
+use tracing::error;
 
-use crate::{tiers::Tier, Consumer, State};
+use crate::{
+    quota::{quota, QuotaPeriod},
+    redis::{Cmd, RedisClient, RedisError},
+    tiers::{LimitMode, Tier, TierRate},
+    Consumer, State,
+};
+
+pub const REDIS_PREFIX: &str = "ogmios-proxy";
+
+/// Token bucket of a rate, refilled `limit` tokens at the end of every interval. Unused tokens
+/// accumulate up to `MAX_INTERVALS` intervals.
+struct Bucket {
+    rate: TierRate,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    balance: usize,
+    refilled: Instant,
+}
+
+const MAX_INTERVALS: usize = 10;
+
+impl Bucket {
+    fn new(rate: &TierRate, now: Instant) -> Self {
+        Self {
+            rate: rate.clone(),
+            state: Mutex::new(BucketState {
+                balance: rate.limit,
+                refilled: now,
+            }),
+        }
+    }
+
+    fn max(&self) -> usize {
+        self.rate.limit.saturating_mul(MAX_INTERVALS)
+    }
+
+    /// Tokens charged for the method, a cost above the max could never be charged.
+    fn cost(&self, method: Option<&str>) -> usize {
+        self.rate.cost(method).min(self.max())
+    }
+
+    /// Adds the tokens of the intervals elapsed since the last refill.
+    fn refill(&self, state: &mut BucketState, now: Instant) {
+        let interval = self.rate.interval.max(Duration::from_millis(1));
+        let intervals = (now.saturating_duration_since(state.refilled).as_millis()
+            / interval.as_millis()) as u32;
+        if intervals == 0 {
+            return;
+        }
+
+        state.balance = state
+            .balance
+            .saturating_add(self.rate.limit.saturating_mul(intervals as usize))
+            .min(self.max());
+        state.refilled += interval * intervals;
+    }
+
+    /// Time until the bucket holds the given number of tokens.
+    fn refill_time(&self, state: &BucketState, tokens: usize, now: Instant) -> Duration {
+        let missing = tokens.saturating_sub(state.balance);
+        if missing == 0 {
+            return Duration::ZERO;
+        }
+
+        let intervals = missing.div_ceil(self.rate.limit.max(1)) as u32;
+        (state.refilled + self.rate.interval * intervals).saturating_duration_since(now)
+    }
+
+    /// State of the bucket as exposed to the clients.
+    fn rate_limit(&self, state: &BucketState, now: Instant) -> RateLimit {
+        let limit = self.rate.limit;
+        // Unused tokens accumulate over several intervals, so the balance can exceed the limit.
+        let remaining = state.balance.min(limit);
+        RateLimit {
+            limit,
+            remaining,
+            reset: self.refill_time(state, limit, now),
+        }
+    }
+}
+
+/// Limit of the most constrained rate of the tier, exposed to http clients as headers.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RateLimit {
+    pub limit: usize,
+    pub remaining: usize,
+    pub reset: Duration,
+}
 
 #[derive(Debug)]
 pub enum LimiterError {
     PortDeleted,
     InvalidTier,
+    RateLimited {
+        rate_limit: RateLimit,
+        retry_after: Duration,
+    },
+    ByteQuotaExceeded {
+        retry_after: Duration,
+    },
+    QuotaExceeded {
+        period: QuotaPeriod,
+        retry_after: Duration,
+    },
 }
 impl Display for LimiterError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             LimiterError::PortDeleted => f.write_str("Port was deleted"),
             LimiterError::InvalidTier => f.write_str("Tier is invalid"),
+            LimiterError::RateLimited { .. } => f.write_str("Rate limit exceeded"),
+            LimiterError::ByteQuotaExceeded { .. } => f.write_str("Byte quota exceeded"),
+            LimiterError::QuotaExceeded { period, .. } => {
+                write!(f, "Quota exceeded for the {period} period")
+            }
         }
     }
 }
 impl Error for LimiterError {}
 
-async fn has_limiter(state: &State, consumer: &Consumer) -> bool {
-    let rate_limiter_map = state.limiter.read().await;
-    rate_limiter_map.get(&consumer.key).is_some()
-}
-
-async fn add_limiter(state: &State, consumer: &Consumer, tier: &Tier) {
-    let rates = tier
-        .rates
-        .iter()
-        .map(|r| {
-            Arc::new(
-                RateLimiter::builder()
-                    .initial(r.limit)
-                    .interval(r.interval)
-                    .refill(r.limit)
-                    .build(),
-            )
-        })
-        .collect();
+#[async_trait]
+pub trait LimiterBackend: Send + Sync {
+    /// Charges the cost of the method to every rate. Waits for the tokens up to `max_wait`, or
+    /// as long as needed when `None`, and returns the state of the most constrained rate.
+    async fn acquire(
+        &self,
+        consumer: &Consumer,
+        rates: &[TierRate],
+        method: Option<&str>,
+        max_wait: Option<Duration>,
+    ) -> Result<Option<RateLimit>, LimiterError>;
+
+    /// Forgets the state of the consumer, e.g. when its port is updated.
+    async fn remove(&self, consumer: &Consumer);
+
+    /// Forgets the state of every consumer, e.g. when the tiers are reloaded.
+    async fn clear(&self);
+}
+
+/// Shared store backend when `PROXY_REDIS_URL` is set, so the limits hold across every proxy
+/// replica. Otherwise each replica limits on its own.
+pub fn build_backend(redis: Option<Arc<RedisClient>>) -> Box<dyn LimiterBackend> {
+    match redis {
+        Some(client) => Box::new(RedisLimiter::new(client)),
+        None => Box::<MemoryLimiter>::default(),
+    }
+}
+
+/// Token buckets kept in the proxy memory, refilled `limit` tokens per interval.
+#[derive(Default)]
+pub struct MemoryLimiter {
+    buckets: RwLock<HashMap<String, Arc<Vec<Bucket>>>>,
+}
+impl MemoryLimiter {
+    async fn buckets(&self, consumer: &Consumer, rates: &[TierRate]) -> Arc<Vec<Bucket>> {
+        if let Some(buckets) = self.buckets.read().await.get(&consumer.id) {
+            return buckets.clone();
+        }
+
+        let now = Instant::now();
+        let buckets = Arc::new(rates.iter().map(|r| Bucket::new(r, now)).collect());
+
+        self.buckets
+            .write()
+            .await
+            .entry(consumer.id.clone())
+            .or_insert(buckets)
+            .clone()
+    }
+
+    /// Charges every rate at once, or none of them when a rate is short of tokens. In that case
+    /// the state of the rate that takes the longest to refill is returned.
+    fn try_acquire(
+        buckets: &[Bucket],
+        method: Option<&str>,
+    ) -> Result<Option<RateLimit>, (RateLimit, Duration)> {
+        let now = Instant::now();
+        // Locked in the order of the tier rates, so concurrent requests can't deadlock.
+        let mut states: Vec<_> = buckets
+            .iter()
+            .map(|b| b.state.lock().unwrap_or_else(|e| e.into_inner()))
+            .collect();
+        for (bucket, state) in buckets.iter().zip(states.iter_mut()) {
+            bucket.refill(state, now);
+        }
+
+        let exhausted = buckets
+            .iter()
+            .zip(states.iter())
+            .map(|(b, s)| (b.rate_limit(s, now), b.refill_time(s, b.cost(method), now)))
+            .filter(|(_, retry_after)| !retry_after.is_zero())
+            .max_by_key(|(_, retry_after)| *retry_after);
+        if let Some(exhausted) = exhausted {
+            return Err(exhausted);
+        }
+
+        Ok(buckets
+            .iter()
+            .zip(states.iter_mut())
+            .map(|(bucket, state)| {
+                state.balance -= bucket.cost(method);
+                bucket.rate_limit(state, now)
+            })
+            .min_by_key(|rate_limit| rate_limit.remaining))
+    }
+}
+#[async_trait]
+impl LimiterBackend for MemoryLimiter {
+    async fn acquire(
+        &self,
+        consumer: &Consumer,
+        rates: &[TierRate],
+        method: Option<&str>,
+        max_wait: Option<Duration>,
+    ) -> Result<Option<RateLimit>, LimiterError> {
+        let buckets = self.buckets(consumer, rates).await;
+
+        let started = Instant::now();
+        loop {
+            let (rate_limit, retry_after) = match Self::try_acquire(&buckets, method) {
+                Ok(rate_limit) => return Ok(rate_limit),
+                Err(exhausted) => exhausted,
+            };
+
+            if let Some(max_wait) = max_wait {
+                if started.elapsed() + retry_after > max_wait {
+                    return Err(LimiterError::RateLimited {
+                        rate_limit,
+                        retry_after,
+                    });
+                }
+            }
+            tokio::time::sleep(retry_after).await;
+        }
+    }
+
+    async fn remove(&self, consumer: &Consumer) {
+        self.buckets.write().await.remove(&consumer.id);
+    }
+
+    async fn clear(&self) {
+        self.buckets.write().await.clear();
+    }
+}
+
+/// Fixed window counters kept in a Redis compatible store and shared by the proxy replicas.
+/// Windows are aligned to the unix epoch, so every replica counts in the same window. While the
+/// store fails, the replica limits on its own with token buckets in memory.
+pub struct RedisLimiter {
+    client: Arc<RedisClient>,
+    fallback: MemoryLimiter,
+}
+impl RedisLimiter {
+    pub fn new(client: Arc<RedisClient>) -> Self {
+        Self {
+            client,
+            fallback: MemoryLimiter::default(),
+        }
+    }
+
+    /// Charges every rate at once. When a rate is exhausted, the tokens are given back and the
+    /// time until the end of the window of the exhausted rate is returned.
+    async fn try_acquire(
+        &self,
+        consumer: &Consumer,
+        rates: &[TierRate],
+        method: Option<&str>,
+    ) -> Result<Result<Option<RateLimit>, (RateLimit, Duration)>, RedisError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        let windows: Vec<_> = rates
+            .iter()
+            .map(|rate| {
+                let interval = (rate.interval.as_millis() as u64).max(1);
+                let window = now / interval;
+                let key = format!(
+                    "{REDIS_PREFIX}:rate:{consumer}:{}-{interval}:{window}",
+                    rate.limit
+                );
+                // A cost above the limit could never be charged in a single window.
+                let cost = rate.cost(method).min(rate.limit);
+                let reset = Duration::from_millis((window + 1) * interval - now);
+                (key, cost, rate.limit, interval, reset)
+            })
+            .collect();
+
+        let cmds: Vec<Cmd> = windows
+            .iter()
+            .flat_map(|(key, cost, _, interval, _)| {
+                [
+                    Cmd::new("INCRBY").arg(key).arg(cost),
+                    Cmd::new("PEXPIRE").arg(key).arg(interval),
+                ]
+            })
+            .collect();
+        let replies = self.client.pipeline(&cmds).await?;
+
+        let mut rate_limits = Vec::with_capacity(windows.len());
+        let mut exhausted = None;
+        for ((_, _, limit, _, reset), reply) in windows.iter().zip(replies.iter().step_by(2)) {
+            let count = reply
+                .as_integer()
+                .ok_or(RedisError::Protocol("INCRBY must reply an integer".into()))?;
+            let rate_limit = RateLimit {
+                limit: *limit,
+                remaining: limit.saturating_sub(count.max(0) as usize),
+                reset: *reset,
+            };
+            if count > *limit as i64 && exhausted.map_or(true, |(_, r)| *reset > r) {
+                exhausted = Some((rate_limit, *reset));
+            }
+            rate_limits.push(rate_limit);
+        }
+
+        if let Some(exhausted) = exhausted {
+            let cmds: Vec<Cmd> = windows
+                .iter()
+                .map(|(key, cost, ..)| Cmd::new("DECRBY").arg(key).arg(cost))
+                .collect();
+            self.client.pipeline(&cmds).await?;
+            return Ok(Err(exhausted));
+        }
+
+        Ok(Ok(rate_limits
+            .into_iter()
+            .min_by_key(|rate_limit| rate_limit.remaining)))
+    }
+}
+#[async_trait]
+impl LimiterBackend for RedisLimiter {
+    async fn acquire(
+        &self,
+        consumer: &Consumer,
+        rates: &[TierRate],
+        method: Option<&str>,
+        max_wait: Option<Duration>,
+    ) -> Result<Option<RateLimit>, LimiterError> {
+        let started = Instant::now();
+        loop {
+            let (rate_limit, retry_after) = match self.try_acquire(consumer, rates, method).await {
+                Ok(Ok(rate_limit)) => return Ok(rate_limit),
+                Ok(Err(exhausted)) => exhausted,
+                // Fails open, see `crate::redis`.
+                Err(err) => {
+                    error!(error = err.to_string(), "fail to run redis limiter");
+                    let max_wait = max_wait.map(|w| w.saturating_sub(started.elapsed()));
+                    return self
+                        .fallback
+                        .acquire(consumer, rates, method, max_wait)
+                        .await;
+                }
+            };
+
+            if let Some(max_wait) = max_wait {
+                if started.elapsed() + retry_after > max_wait {
+                    return Err(LimiterError::RateLimited {
+                        rate_limit,
+                        retry_after,
+                    });
+                }
+            }
+            tokio::time::sleep(retry_after).await;
+        }
+    }
+
+    // Windows are keyed by the rate, so a new tier starts new counters by itself.
+    async fn remove(&self, consumer: &Consumer) {
+        self.fallback.remove(consumer).await;
+    }
+
+    async fn clear(&self) {
+        self.fallback.clear().await;
+    }
+}
+
+/// Tier of the port as currently known, the port or its tier may have changed since the
+/// connection started.
+pub async fn consumer_tier(state: &State, consumer: &Consumer) -> Result<Tier, LimiterError> {
+    let tier = match state.consumers.read().await.get(&consumer.id) {
+        Some(consumer) => consumer.tier.clone(),
+        None => return Err(LimiterError::PortDeleted),
+    };
+
+    match state.tiers.read().await.get(&tier) {
+        Some(tier) => Ok(tier.clone()),
+        None => Err(LimiterError::InvalidTier),
+    }
+}
+
+pub async fn limiter(
+    state: Arc<State>,
+    consumer: &Consumer,
+    method: Option<&str>,
+) -> Result<(), LimiterError> {
+    let tier = consumer_tier(&state, consumer).await?;
+    let max_wait = match tier.on_limit {
+        LimitMode::Queue => None,
+        LimitMode::Reject => Some(tier.max_wait),
+    };
 
     state
         .limiter
-        .write()
-        .await
-        .insert(consumer.key.clone(), rates);
+        .acquire(consumer, &tier.rates, method, max_wait)
+        .await?;
+    Ok(())
+}
+
+/// Charges the consumer without waiting for tokens, the request is rejected when any rate of
+/// the tier is exhausted.
+pub async fn try_limiter(
+    state: Arc<State>,
+    consumer: &Consumer,
+    method: Option<&str>,
+) -> Result<Option<RateLimit>, LimiterError> {
+    let tier = consumer_tier(&state, consumer).await?;
+    let rate_limit = state
+        .limiter
+        .acquire(consumer, &tier.rates, method, Some(Duration::ZERO))
+        .await?;
+    Ok(rate_limit)
 }
 
-pub async fn limiter(state: Arc<State>, consumer: &Consumer) -> Result<(), LimiterError> {
-    if !has_limiter(&state, consumer).await {
-        let consumers = state.consumers.read().await.clone();
-        let refreshed_consumer = match consumers.get(&consumer.key) {
-            Some(consumer) => consumer,
-            None => return Err(LimiterError::PortDeleted),
+/// Counts a request against the quotas of the tier. Charged last, once every other limit let
+/// the request through, so rejected requests don't use up the quotas.
+pub async fn request_quota(state: &State, consumer: &Consumer) -> Result<(), LimiterError> {
+    let tier = consumer_tier(state, consumer).await?;
+    quota(state, consumer, &tier).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redis::stand_in;
+
+    fn rate(limit: usize) -> TierRate {
+        TierRate {
+            limit,
+            interval: Duration::from_secs(3600),
+            default_cost: 1,
+            costs: HashMap::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_without_charging_the_other_rates() {
+        let limiter = MemoryLimiter::default();
+        let consumer = Consumer {
+            id: "port".into(),
+            ..Default::default()
         };
-        let tiers = state.tiers.read().await.clone();
-        let tier = match tiers.get(&refreshed_consumer.tier) {
-            Some(tier) => tier,
-            None => return Err(LimiterError::InvalidTier),
+        let rates = [rate(1), rate(10)];
+
+        let rate_limit = limiter
+            .acquire(&consumer, &rates, None, Some(Duration::ZERO))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(rate_limit.remaining, 0);
+
+        for _ in 0..3 {
+            let result = limiter
+                .acquire(&consumer, &rates, None, Some(Duration::ZERO))
+                .await;
+            match result {
+                Err(LimiterError::RateLimited { rate_limit, .. }) => {
+                    assert_eq!(rate_limit.limit, 1)
+                }
+                other => panic!("expected a rate limit error, got {other:?}"),
+            }
+        }
+
+        let buckets = limiter.buckets(&consumer, &rates).await;
+        let balances: Vec<_> = buckets
+            .iter()
+            .map(|b| b.state.lock().unwrap().balance)
+            .collect();
+        assert_eq!(balances, [0, 9]);
+    }
+
+    #[tokio::test]
+    async fn refills_the_tokens_of_every_elapsed_interval() {
+        let bucket = Bucket::new(&rate(10), Instant::now());
+        let mut state = bucket.state.lock().unwrap();
+        state.balance = 0;
+        let refilled = state.refilled;
+
+        let now = refilled + Duration::from_secs(3600 * 2 + 60);
+        bucket.refill(&mut state, now);
+        assert_eq!(state.balance, 20);
+        assert_eq!(state.refilled, refilled + Duration::from_secs(3600 * 2));
+        assert_eq!(
+            bucket.refill_time(&state, 25, now),
+            Duration::from_secs(3600 - 60)
+        );
+
+        bucket.refill(&mut state, now + Duration::from_secs(3600 * 100));
+        assert_eq!(state.balance, 100);
+    }
+
+    #[tokio::test]
+    async fn redis_gives_back_the_tokens_of_rejected_requests() {
+        let (url, keys) = stand_in::start().await;
+        let client = RedisClient::new(&url, Duration::from_secs(1)).unwrap();
+        let limiter = RedisLimiter::new(Arc::new(client));
+        let consumer = Consumer {
+            namespace: "ns".into(),
+            port_name: "port".into(),
+            ..Default::default()
         };
-        add_limiter(&state, refreshed_consumer, tier).await;
+        let rates = [rate(1), rate(10)];
+
+        let rate_limit = limiter
+            .acquire(&consumer, &rates, None, Some(Duration::ZERO))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(rate_limit.remaining, 0);
+
+        for _ in 0..3 {
+            let result = limiter
+                .acquire(&consumer, &rates, None, Some(Duration::ZERO))
+                .await;
+            assert!(matches!(result, Err(LimiterError::RateLimited { .. })));
+        }
+
+        let keys = keys.lock().unwrap();
+        assert_eq!(keys.len(), 2);
+        for (key, value) in keys.iter() {
+            assert!(key.starts_with("ogmios-proxy:rate:ns.port:"));
+            assert_eq!(*value, stand_in::Entry::Integer(1));
+        }
     }
 
-    let rate_limiter_map = state.limiter.read().await.clone();
-    let rates = rate_limiter_map.get(&consumer.key).unwrap();
+    #[tokio::test]
+    async fn redis_falls_back_to_memory_while_redis_fails() {
+        let url = stand_in::unreachable().await;
+        let client = RedisClient::new(&url, Duration::from_secs(1)).unwrap();
+        let limiter = RedisLimiter::new(Arc::new(client));
+        let consumer = Consumer::default();
+        let rates = [rate(1)];
 
-    join_all(rates.iter().map(|r| async { r.acquire_one().await })).await;
-    Ok(())
+        assert!(limiter
+            .acquire(&consumer, &rates, None, Some(Duration::ZERO))
+            .await
+            .is_ok());
+        assert!(matches!(
+            limiter
+                .acquire(&consumer, &rates, None, Some(Duration::ZERO))
+                .await,
+            Err(LimiterError::RateLimited { .. })
+        ));
+    }
 }
@@ -1,25 +1,48 @@
+use bandwidth::Bandwidth;
+use cache::Cache;
 use config::Config;
+use connections::ConnectionLeases;
 use dotenv::dotenv;
-use leaky_bucket::RateLimiter;
+use health::InstanceHealth;
+use limiter::LimiterBackend;
 use metrics::Metrics;
 use operator::{kube::ResourceExt, OgmiosPort};
 use prometheus::Registry;
+use proxy::ListenerMode;
+use quota::QuotaStore;
+use redis::RedisClient;
 use regex::Regex;
+use shutdown::Shutdown;
 use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::Display;
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tiers::Tier;
+use tls::Tls;
 use tokio::sync::RwLock;
 use tracing::Level;
+use upstream::{HttpClient, UpstreamPool};
 
 mod auth;
+mod bandwidth;
+mod cache;
 mod config;
+mod connections;
+mod health;
+mod jsonrpc;
 mod limiter;
 mod metrics;
 mod proxy;
+mod quota;
+mod redis;
+mod shutdown;
 mod tiers;
+mod tls;
+mod translate;
+mod upstream;
 mod utils;
+mod websocket;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -31,11 +54,19 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     auth::start(state.clone());
     tiers::start(state.clone());
+    tls::start(state.clone());
+    health::start(state.clone());
+    connections::start(state.clone());
+    quota::start(state.clone());
 
     let metrics = metrics::start(state.clone());
     let proxy_server = proxy::start(state.clone());
+    let shutdown = shutdown::start(state.clone());
 
-    tokio::join!(metrics, proxy_server);
+    tokio::select! {
+        _ = async { tokio::join!(metrics, proxy_server) } => {},
+        _ = shutdown => {},
+    }
 
     Ok(())
 }
@@ -45,8 +76,18 @@ pub struct State {
     metrics: Metrics,
     host_regex: Regex,
     consumers: RwLock<HashMap<String, Consumer>>,
+    tokens: RwLock<HashMap<String, ConsumerToken>>,
     tiers: RwLock<HashMap<String, Tier>>,
-    limiter: RwLock<HashMap<String, Vec<Arc<RateLimiter>>>>,
+    limiter: Box<dyn LimiterBackend>,
+    connection_leases: Option<Arc<ConnectionLeases>>,
+    bandwidth: Bandwidth,
+    quotas: Box<dyn QuotaStore>,
+    instances_health: RwLock<HashMap<String, InstanceHealth>>,
+    upstreams: RwLock<HashMap<String, Arc<UpstreamPool>>>,
+    http_client: HttpClient,
+    cache: Cache,
+    shutdown: Shutdown,
+    tls: Option<Tls>,
 }
 impl State {
     pub fn try_new() -> Result<Self, Box<dyn Error>> {
@@ -54,22 +95,89 @@ impl State {
         let metrics = Metrics::try_new(Registry::default())?;
         let host_regex = Regex::new(r"(dmtr_[\w\d-]+)?\.?.+")?;
         let consumers = Default::default();
+        let tokens = Default::default();
         let tiers = Default::default();
-        let limiter = Default::default();
+        let redis = match &config.proxy_redis_url {
+            Some(url) => Some(Arc::new(RedisClient::new(url, config.proxy_redis_timeout)?)),
+            None => None,
+        };
+        let limiter = limiter::build_backend(redis.clone());
+        let quotas = quota::build_store(&config, redis.clone());
+        let connection_leases = redis.map(|client| {
+            Arc::new(ConnectionLeases::new(
+                client,
+                config.proxy_connection_lease_ttl,
+            ))
+        });
+        let bandwidth = Default::default();
+        let instances_health = Default::default();
+        let upstreams = RwLock::new(upstream::build_pools(&config, &metrics));
+        let http_client = upstream::build_http_client(&config);
+        let cache = Cache::new(config.proxy_cache_ttl.clone());
+        let shutdown = Shutdown::default();
+        let tls = match config.proxy_listener_mode {
+            ListenerMode::Plaintext => None,
+            ListenerMode::Tls | ListenerMode::Dual => Some(Tls::try_new(&config, &metrics)?),
+        };
 
         Ok(Self {
             config,
             metrics,
             host_regex,
             consumers,
+            tokens,
             tiers,
             limiter,
+            connection_leases,
+            bandwidth,
+            quotas,
+            instances_health,
+            upstreams,
+            http_client,
+            cache,
+            shutdown,
+            tls,
         })
     }
 
-    pub async fn get_consumer(&self, key: &str) -> Option<Consumer> {
-        self.consumers.read().await.clone().get(key).cloned()
+    /// Port of the auth token, unless the token expired.
+    pub async fn get_consumer(&self, token: &str) -> Option<Consumer> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64;
+        let id = match self.tokens.read().await.get(token) {
+            Some(token) if token.not_after.map_or(true, |not_after| not_after > now) => {
+                token.consumer.clone()
+            }
+            _ => return None,
+        };
+        self.consumers.read().await.get(&id).cloned()
+    }
+
+    /// Version of the instances serving a port. With the v5 translation enabled, v5 ports are
+    /// served by v6 instances.
+    pub fn upstream_version<'a>(&self, version: &'a str) -> &'a str {
+        match self.config.proxy_v5_translation && version == "5" {
+            true => "6",
+            false => version,
+        }
     }
+
+    pub fn instance_addr(&self, network: &str, version: &str) -> String {
+        format!(
+            "ogmios-{}-{}.{}:{}",
+            network, version, self.config.ogmios_dns, self.config.ogmios_port
+        )
+    }
+}
+
+/// Port an auth token belongs to.
+#[derive(Debug, Clone)]
+pub struct ConsumerToken {
+    consumer: String,
+    // milliseconds since the unix epoch, the token never expires when not set
+    not_after: Option<i64>,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -77,7 +185,8 @@ pub struct Consumer {
     namespace: String,
     port_name: String,
     tier: String,
-    key: String,
+    // stable identity of the port, the auth tokens of the port may change
+    id: String,
     network: String,
     version: String,
     active_connections: usize,
@@ -92,15 +201,15 @@ impl From<&OgmiosPort> for Consumer {
         let network = value.spec.network.to_string();
         let version = value.spec.version.to_string();
         let tier = value.spec.throughput_tier.to_string();
-        let key = value.status.as_ref().unwrap().auth_token.clone();
         let namespace = value.metadata.namespace.as_ref().unwrap().clone();
         let port_name = value.name_any();
+        let id = format!("{namespace}.{port_name}");
 
         Self {
             namespace,
             port_name,
             tier,
-            key,
+            id,
             network,
             version,
             active_connections: 0,
@@ -113,7 +222,7 @@ impl Consumer {
             .consumers
             .write()
             .await
-            .entry(self.key.clone())
+            .entry(self.id.clone())
             .and_modify(|consumer| consumer.active_connections += 1);
     }
     pub async fn dec_connections(&self, state: Arc<State>) {
@@ -121,15 +230,17 @@ impl Consumer {
             .consumers
             .write()
             .await
-            .entry(self.key.clone())
-            .and_modify(|consumer| consumer.active_connections -= 1);
+            .entry(self.id.clone())
+            .and_modify(|consumer| {
+                consumer.active_connections = consumer.active_connections.saturating_sub(1)
+            });
     }
     pub async fn get_active_connections(&self, state: Arc<State>) -> usize {
         state
             .consumers
             .read()
             .await
-            .get(&self.key)
+            .get(&self.id)
             .map(|consumer| consumer.active_connections)
             .unwrap_or_default()
     }
@@ -5,20 +5,89 @@ use std::{net::SocketAddr, str::FromStr};
 use hyper::server::conn::http1 as http1_server;
 use hyper::{body::Incoming, service::service_fn, Method, Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
-use prometheus::{opts, Encoder, IntCounterVec, IntGaugeVec, Registry, TextEncoder};
+use prometheus::{opts, Encoder, GaugeVec, IntCounterVec, IntGaugeVec, Registry, TextEncoder};
 use tokio::net::TcpListener;
 use tracing::{error, info, instrument};
 
-use crate::proxy::ProxyRequest;
+use crate::jsonrpc::UNKNOWN_METHOD;
+use crate::proxy::{handle_healthz, ProxyRequest};
 use crate::utils::{full, ProxyResponse};
 use crate::State;
 
+// Methods of Ogmios v6 (JSON-RPC) and v5 (JSON-WSP). Method labels come from the clients, so
+// any other method is counted as unknown to bound the cardinality of the metrics.
+const OGMIOS_METHODS: &[&str] = &[
+    "findIntersection",
+    "nextBlock",
+    "submitTransaction",
+    "evaluateTransaction",
+    "acquireLedgerState",
+    "releaseLedgerState",
+    "acquireMempool",
+    "nextTransaction",
+    "hasTransaction",
+    "sizeOfMempool",
+    "releaseMempool",
+    "queryLedgerState/constitution",
+    "queryLedgerState/constitutionalCommittee",
+    "queryLedgerState/delegateRepresentatives",
+    "queryLedgerState/dump",
+    "queryLedgerState/epoch",
+    "queryLedgerState/eraStart",
+    "queryLedgerState/eraSummaries",
+    "queryLedgerState/governanceProposals",
+    "queryLedgerState/liveStakeDistribution",
+    "queryLedgerState/nonces",
+    "queryLedgerState/operationalCertificates",
+    "queryLedgerState/projectedRewards",
+    "queryLedgerState/proposedProtocolParameters",
+    "queryLedgerState/protocolParameters",
+    "queryLedgerState/rewardAccountSummaries",
+    "queryLedgerState/rewardsProvenance",
+    "queryLedgerState/stakePools",
+    "queryLedgerState/stakePoolsPerformances",
+    "queryLedgerState/tip",
+    "queryLedgerState/treasuryAndReserves",
+    "queryLedgerState/utxo",
+    "queryNetwork/blockHeight",
+    "queryNetwork/genesisConfiguration",
+    "queryNetwork/startTime",
+    "queryNetwork/tip",
+    "FindIntersect",
+    "RequestNext",
+    "SubmitTx",
+    "EvaluateTx",
+    "Acquire",
+    "Release",
+    "Query",
+    "AwaitAcquire",
+    "NextTx",
+    "HasTx",
+    "SizeAndCapacity",
+    "ReleaseMempool",
+];
+
+fn method_label(method: &str) -> &str {
+    match OGMIOS_METHODS.contains(&method) {
+        true => method,
+        false => UNKNOWN_METHOD,
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Metrics {
     registry: Registry,
     pub ws_total_frame: IntCounterVec,
+    pub ws_total_request: IntCounterVec,
+    pub ws_total_response: IntCounterVec,
     pub ws_total_connection: IntGaugeVec,
     pub http_total_request: IntCounterVec,
+    pub instance_healthy: IntGaugeVec,
+    pub instance_network_synchronization: GaugeVec,
+    pub instance_tip_slot: IntGaugeVec,
+    pub upstream_active_connections: IntGaugeVec,
+    pub cache_total_request: IntCounterVec,
+    pub tls_certificate_expiry: IntGaugeVec,
 }
 
 impl Metrics {
@@ -29,6 +98,39 @@ impl Metrics {
         )
         .unwrap();
 
+        let ws_total_request = IntCounterVec::new(
+            opts!(
+                "ogmios_proxy_ws_total_request",
+                "total of websocket json-rpc request by method",
+            ),
+            &[
+                "namespace",
+                "instance",
+                "route",
+                "consumer",
+                "tier",
+                "method",
+            ],
+        )
+        .unwrap();
+
+        let ws_total_response = IntCounterVec::new(
+            opts!(
+                "ogmios_proxy_ws_total_response",
+                "total of websocket json-rpc response by method",
+            ),
+            &[
+                "namespace",
+                "instance",
+                "route",
+                "consumer",
+                "tier",
+                "method",
+                "status",
+            ],
+        )
+        .unwrap();
+
         let ws_total_connection = IntGaugeVec::new(
             opts!(
                 "ogmios_proxy_total_connections",
@@ -52,15 +154,85 @@ impl Metrics {
         )
         .unwrap();
 
+        let instance_healthy = IntGaugeVec::new(
+            opts!(
+                "ogmios_proxy_instance_healthy",
+                "ogmios instance health status, 1 when healthy",
+            ),
+            &["instance"],
+        )
+        .unwrap();
+
+        let instance_network_synchronization = GaugeVec::new(
+            opts!(
+                "ogmios_proxy_instance_network_synchronization",
+                "ogmios instance network synchronization",
+            ),
+            &["instance"],
+        )
+        .unwrap();
+
+        let instance_tip_slot = IntGaugeVec::new(
+            opts!(
+                "ogmios_proxy_instance_tip_slot",
+                "ogmios instance last known tip slot",
+            ),
+            &["instance"],
+        )
+        .unwrap();
+
+        let upstream_active_connections = IntGaugeVec::new(
+            opts!(
+                "ogmios_proxy_upstream_active_connections",
+                "total of active connections by upstream",
+            ),
+            &["upstream"],
+        )
+        .unwrap();
+
+        let cache_total_request = IntCounterVec::new(
+            opts!(
+                "ogmios_proxy_cache_total_request",
+                "total of cacheable requests by result",
+            ),
+            &["instance", "method", "result"],
+        )
+        .unwrap();
+
+        let tls_certificate_expiry = IntGaugeVec::new(
+            opts!(
+                "ogmios_proxy_tls_certificate_expiry_timestamp_seconds",
+                "unix time when the tls certificate served by the proxy expires",
+            ),
+            &["certificate"],
+        )
+        .unwrap();
+
         registry.register(Box::new(ws_total_frame.clone()))?;
+        registry.register(Box::new(ws_total_request.clone()))?;
+        registry.register(Box::new(ws_total_response.clone()))?;
         registry.register(Box::new(ws_total_connection.clone()))?;
         registry.register(Box::new(http_total_request.clone()))?;
+        registry.register(Box::new(instance_healthy.clone()))?;
+        registry.register(Box::new(instance_network_synchronization.clone()))?;
+        registry.register(Box::new(instance_tip_slot.clone()))?;
+        registry.register(Box::new(upstream_active_connections.clone()))?;
+        registry.register(Box::new(cache_total_request.clone()))?;
+        registry.register(Box::new(tls_certificate_expiry.clone()))?;
 
         Ok(Metrics {
             registry,
             ws_total_frame,
+            ws_total_request,
+            ws_total_response,
             ws_total_connection,
             http_total_request,
+            instance_healthy,
+            instance_network_synchronization,
+            instance_tip_slot,
+            upstream_active_connections,
+            cache_total_request,
+            tls_certificate_expiry,
         })
     }
 
@@ -80,6 +252,33 @@ impl Metrics {
             .inc()
     }
 
+    pub fn count_ws_total_request(&self, proxy_req: &ProxyRequest, method: &str) {
+        self.ws_total_request
+            .with_label_values(&[
+                &proxy_req.namespace,
+                &proxy_req.instance,
+                &proxy_req.host,
+                &proxy_req.consumer.to_string(),
+                &proxy_req.consumer.tier,
+                method_label(method),
+            ])
+            .inc()
+    }
+
+    pub fn count_ws_total_response(&self, proxy_req: &ProxyRequest, method: &str, status: &str) {
+        self.ws_total_response
+            .with_label_values(&[
+                &proxy_req.namespace,
+                &proxy_req.instance,
+                &proxy_req.host,
+                &proxy_req.consumer.to_string(),
+                &proxy_req.consumer.tier,
+                method_label(method),
+                status,
+            ])
+            .inc()
+    }
+
     pub fn inc_ws_total_connection(&self, proxy_req: &ProxyRequest) {
         self.ws_total_connection
             .with_label_values(&[
@@ -117,6 +316,42 @@ impl Metrics {
             ])
             .inc()
     }
+
+    pub fn count_cache_request(&self, proxy_req: &ProxyRequest, method: &str, result: &str) {
+        self.cache_total_request
+            .with_label_values(&[&proxy_req.instance, method_label(method), result])
+            .inc()
+    }
+
+    pub fn set_instance_health(
+        &self,
+        instance: &str,
+        healthy: bool,
+        network_synchronization: f64,
+        tip_slot: Option<u64>,
+    ) {
+        self.instance_healthy
+            .with_label_values(&[instance])
+            .set(healthy as i64);
+        self.instance_network_synchronization
+            .with_label_values(&[instance])
+            .set(network_synchronization);
+        if let Some(slot) = tip_slot {
+            self.instance_tip_slot
+                .with_label_values(&[instance])
+                .set(slot as i64);
+        }
+    }
+
+    /// Replaces the expiry of the certificates served, so removed certificates aren't reported.
+    pub fn set_tls_certificate_expiry(&self, certificates: &[(String, i64)]) {
+        self.tls_certificate_expiry.reset();
+        for (certificate, not_after) in certificates {
+            self.tls_certificate_expiry
+                .with_label_values(&[certificate])
+                .set(*not_after)
+        }
+    }
 }
 
 async fn api_get_metrics(state: &State) -> Result<ProxyResponse, hyper::Error> {
@@ -136,6 +371,8 @@ async fn routes_match(
 ) -> Result<ProxyResponse, hyper::Error> {
     match (req.method(), req.uri().path()) {
         (&Method::GET, "/metrics") => api_get_metrics(&state).await,
+        // Also served here for the readiness probe, which can't pick a certificate by SNI.
+        (&Method::GET, "/healthz") => handle_healthz(&state).await,
         _ => Ok(Response::builder()
             .status(StatusCode::NOT_FOUND)
             .body(full("Not Found"))
@@ -188,3 +425,19 @@ pub async fn start(state: Arc<State>) {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_unknown_methods() {
+        assert_eq!(
+            method_label("queryLedgerState/utxo"),
+            "queryLedgerState/utxo"
+        );
+        assert_eq!(method_label("SubmitTx"), "SubmitTx");
+        assert_eq!(method_label("queryLedgerState/utxo "), UNKNOWN_METHOD);
+        assert_eq!(method_label("randomMethod123"), UNKNOWN_METHOD);
+    }
+}
@@ -1,41 +1,100 @@
-use futures_util::future::select;
-use futures_util::SinkExt;
-use futures_util::StreamExt;
-use futures_util::TryStreamExt;
-use http_body_util::{combinators::BoxBody, BodyExt};
-use hyper::body::Incoming;
-use hyper::client::conn::http1 as http1_client;
+use http_body_util::{combinators::BoxBody, BodyExt, Full};
+use hyper::body::{Bytes, Incoming};
 use hyper::header::{
-    HeaderValue, CONNECTION, HOST, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE,
+    HeaderMap, HeaderValue, AUTHORIZATION, CONNECTION, CONTENT_LENGTH, CONTENT_TYPE, HOST,
+    RETRY_AFTER, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE,
 };
+use hyper::http::request::Parts;
 use hyper::service::service_fn;
-use hyper::{Method, Request, Response, StatusCode};
+use hyper::{Method, Request, Response, StatusCode, Uri, Version};
 use hyper_util::rt::{TokioExecutor, TokioIo};
 use hyper_util::server::conn::auto::Builder;
-use rustls::ServerConfig;
-use rustls_pki_types::{CertificateDer, PrivateKeyDer};
-use std::error::Error;
+use serde_json::Value;
 use std::fmt::Display;
 use std::net::SocketAddr;
-use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
-use std::{fs, io};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::pin;
-use tokio_rustls::TlsAcceptor;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
 use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
 use tokio_tungstenite::tungstenite::protocol::Role;
-use tokio_tungstenite::{connect_async, WebSocketStream};
+use tokio_tungstenite::WebSocketStream;
 use tracing::{error, info};
-use url::Url;
 
-use crate::limiter::limiter;
-use crate::utils::{full, get_header, ProxyResponse, DMTR_API_KEY};
+use crate::bandwidth::{request_bandwidth, response_bandwidth};
+use crate::cache::{Flight, CACHE_COALESCED, CACHE_HIT, CACHE_MISS};
+use crate::connections::ConnectionLease;
+use crate::jsonrpc::{
+    answers, invalid_request, Envelope, RpcRequest, METHOD_NOT_ALLOWED, METHOD_NOT_ALLOWED_MESSAGE,
+    METHOD_NOT_SUPPORTED,
+};
+use crate::limiter::{request_quota, try_limiter, LimiterError, RateLimit};
+use crate::shutdown::{self, Phase};
+use crate::tiers::is_method_allowed;
+use crate::translate;
+use crate::upstream::{self, UpstreamLease};
+use crate::utils::{
+    full, get_header, ProxyResponse, DMTR_API_KEY, X_FORWARDED_FOR, X_FORWARDED_HOST,
+    X_FORWARDED_PROTO, X_RATE_LIMIT_LIMIT, X_RATE_LIMIT_REMAINING, X_RATE_LIMIT_RESET,
+};
+use crate::websocket;
 use crate::{Consumer, State};
 
+const COALESCE_TIMEOUT: Duration = Duration::from_secs(30);
+const API_KEY_QUERY: &str = "apiKey";
+const API_KEY_PATH_PREFIX: &str = "dmtr_";
+
+/// Listeners of the proxy. Plaintext listeners are meant to run behind a tls terminating
+/// ingress, so the `X-Forwarded-*` headers of their requests are trusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenerMode {
+    /// `PROXY_ADDR` terminates tls.
+    Tls,
+    /// `PROXY_ADDR` serves plaintext.
+    Plaintext,
+    /// `PROXY_ADDR` terminates tls and `PROXY_PLAINTEXT_ADDR` serves plaintext.
+    Dual,
+}
+impl FromStr for ListenerMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tls" => Ok(ListenerMode::Tls),
+            "plaintext" => Ok(ListenerMode::Plaintext),
+            "dual" => Ok(ListenerMode::Dual),
+            _ => Err(format!("invalid listener mode {s}")),
+        }
+    }
+}
+
+/// Connection a request was received on.
+#[derive(Debug, Clone, Copy)]
+pub struct Connection {
+    pub remote_addr: SocketAddr,
+    pub tls: bool,
+}
+
 pub async fn start(state: Arc<State>) {
-    let addr_result = SocketAddr::from_str(&state.config.proxy_addr);
+    match state.config.proxy_listener_mode {
+        ListenerMode::Tls => listen(state.clone(), &state.config.proxy_addr, true).await,
+        ListenerMode::Plaintext => listen(state.clone(), &state.config.proxy_addr, false).await,
+        ListenerMode::Dual => {
+            let Some(plaintext_addr) = &state.config.proxy_plaintext_addr else {
+                error!("PROXY_PLAINTEXT_ADDR must be set in dual listener mode");
+                std::process::exit(1);
+            };
+            tokio::join!(
+                listen(state.clone(), &state.config.proxy_addr, true),
+                listen(state.clone(), plaintext_addr, false)
+            );
+        }
+    }
+}
+
+async fn listen(state: Arc<State>, addr: &str, tls: bool) {
+    let addr_result = SocketAddr::from_str(addr);
     if let Err(err) = addr_result {
         error!(error = err.to_string(), "invalid proxy addr");
         std::process::exit(1);
@@ -49,57 +108,76 @@ pub async fn start(state: Arc<State>) {
     }
     let listener = listener_result.unwrap();
 
-    let tls_acceptor_result = build_tls_acceptor(&state);
-    if let Err(err) = tls_acceptor_result {
-        error!(error = err.to_string(), "fail to load tls");
-        std::process::exit(1);
-    }
-    let tls_acceptor = tls_acceptor_result.unwrap();
-
-    info!(addr = state.config.proxy_addr, "proxy listening");
+    info!(addr = addr.to_string(), tls, "proxy listening");
 
+    let phase = state.shutdown.subscribe();
     loop {
         let state = state.clone();
-        let accept_result = listener.accept().await;
+        let accept_result = tokio::select! {
+            result = listener.accept() => result,
+            _ = shutdown::wait(phase.clone(), false) => {
+                info!(addr = addr.to_string(), "proxy stopped accepting connections");
+                return;
+            }
+        };
         if let Err(err) = accept_result {
             error!(error = err.to_string(), "fail to accept client");
             continue;
         }
-        let (stream, _) = accept_result.unwrap();
+        let (stream, remote_addr) = accept_result.unwrap();
+        let connection = Connection { remote_addr, tls };
+        let in_flight = state.shutdown.track();
 
-        let tls_acceptor = tls_acceptor.clone();
+        let tls_acceptor = state.tls.as_ref().filter(|_| tls).map(|tls| tls.acceptor());
+        let phase = phase.clone();
 
         tokio::spawn(async move {
-            let tls_stream = match tls_acceptor.accept(stream).await {
-                Ok(tls_stream) => tls_stream,
-                Err(err) => {
-                    error!(error = err.to_string(), "failed to perform tls handshake");
-                    return;
-                }
+            let _in_flight = in_flight;
+            let Some(tls_acceptor) = tls_acceptor else {
+                return serve(TokioIo::new(stream), connection, phase, state).await;
             };
 
-            let io = TokioIo::new(tls_stream);
-
-            let service = service_fn(move |req| handle(req, state.clone()));
-
-            if let Err(err) = Builder::new(TokioExecutor::new())
-                .serve_connection_with_upgrades(io, service)
-                .await
-            {
-                error!(error = err.to_string(), "failed proxy server connection");
-            }
+            match tls_acceptor.accept(stream).await {
+                Ok(tls_stream) => serve(TokioIo::new(tls_stream), connection, phase, state).await,
+                Err(err) => error!(error = err.to_string(), "failed to perform tls handshake"),
+            };
         });
     }
 }
 
+async fn serve<I>(io: I, connection: Connection, phase: watch::Receiver<Phase>, state: Arc<State>)
+where
+    I: hyper::rt::Read + hyper::rt::Write + Unpin + Send + 'static,
+{
+    let service = service_fn(move |req| handle(req, connection, state.clone()));
+
+    let mut builder = Builder::new(TokioExecutor::new());
+    builder.http2().enable_connect_protocol();
+    let connection = builder.serve_connection_with_upgrades(io, service);
+    tokio::pin!(connection);
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        _ = shutdown::wait(phase, false) => {
+            // Requests in flight are answered before closing the connection, upgraded
+            // connections are drained by their session.
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+    if let Err(err) = result {
+        error!(error = err.to_string(), "failed proxy server connection");
+    }
+}
+
 async fn handle(
     mut hyper_req: Request<Incoming>,
+    connection: Connection,
     state: Arc<State>,
 ) -> Result<ProxyResponse, hyper::Error> {
     match (hyper_req.method(), hyper_req.uri().path()) {
-        (&Method::GET, "/healthz") => handle_healthz().await,
+        (&Method::GET, "/healthz") => handle_healthz(&state).await,
         _ => {
-            let proxy_req_result = ProxyRequest::new(&mut hyper_req, &state).await;
+            let proxy_req_result = ProxyRequest::new(&mut hyper_req, &connection, &state).await;
             if proxy_req_result.is_none() {
                 return Ok(Response::builder()
                     .status(StatusCode::UNAUTHORIZED)
@@ -108,8 +186,30 @@ async fn handle(
             }
 
             let proxy_req = proxy_req_result.unwrap();
+
+            let upstream =
+                match upstream::select(&state, &proxy_req.consumer.network, &proxy_req.version)
+                    .await
+                {
+                    Ok(upstream) => upstream,
+                    Err(health) => {
+                        let response = Response::builder()
+                            .status(StatusCode::SERVICE_UNAVAILABLE)
+                            .body(full(health.reason()))
+                            .unwrap();
+                        state
+                            .metrics
+                            .count_http_total_request(&proxy_req, response.status());
+                        return Ok(response);
+                    }
+                };
+
             let response_result = match proxy_req.protocol {
-                Protocol::Http => handle_http(hyper_req, &proxy_req).await,
+                Protocol::Http => handle_http(hyper_req, &proxy_req, upstream, state.clone()).await,
+                Protocol::Websocket if state.shutdown.is_draining() => Ok(Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .body(full("Proxy is shutting down"))
+                    .unwrap()),
                 Protocol::Websocket => {
                     // Before handling the websocket connection, check if consumer has available
                     // connections.
@@ -117,12 +217,27 @@ async fn handle(
                     match tiers.get(&proxy_req.consumer.tier) {
                         Some(tier) => {
                             if proxy_req.consumer.active_connections >= tier.max_connections {
-                                Ok(Response::builder()
-                                    .status(StatusCode::TOO_MANY_REQUESTS)
-                                    .body(full("Connection limit exceeded"))
-                                    .unwrap())
+                                Ok(connection_limit_exceeded())
                             } else {
-                                handle_websocket(hyper_req, &proxy_req, state.clone()).await
+                                match acquire_connection_lease(
+                                    &state,
+                                    &proxy_req.consumer,
+                                    tier.max_connections,
+                                )
+                                .await
+                                {
+                                    Some(lease) => {
+                                        handle_websocket(
+                                            hyper_req,
+                                            &proxy_req,
+                                            upstream,
+                                            lease,
+                                            state.clone(),
+                                        )
+                                        .await
+                                    }
+                                    None => Ok(connection_limit_exceeded()),
+                                }
                             }
                         }
                         None => Ok(Response::builder()
@@ -155,29 +270,335 @@ async fn handle(
 async fn handle_http(
     hyper_req: Request<Incoming>,
     proxy_req: &ProxyRequest,
+    upstream: UpstreamLease,
+    state: Arc<State>,
 ) -> Result<ProxyResponse, hyper::Error> {
-    let stream = TcpStream::connect(&proxy_req.instance).await.unwrap();
-    let io: TokioIo<TcpStream> = TokioIo::new(stream);
+    let (mut parts, body) = hyper_req.into_parts();
+    // A client aborting the request is answered here, it's not a failure of the proxy.
+    let mut body = match body.collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(err) => {
+            info!(error = err.to_string(), "fail to read the client body");
+            return Ok(bad_request());
+        }
+    };
+    let mut request = RpcRequest::parse(&body);
+
+    // v5 requests are translated to v6 and their responses are translated back.
+    let mut is_v5 = false;
+    if let Some(v5_request) = request
+        .as_ref()
+        .filter(|request| proxy_req.translate() && request.envelope == Envelope::JsonWsp)
+    {
+        match translate::request(v5_request) {
+            Ok(translated) => {
+                body = translated.value().to_string().into();
+                parts.headers.remove(CONTENT_LENGTH);
+                request = Some(translated);
+                is_v5 = true;
+            }
+            Err(reason) => {
+                let error = v5_request.error(METHOD_NOT_SUPPORTED, reason, None);
+                return Ok(Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(full(error.to_string()))
+                    .unwrap());
+            }
+        }
+    }
 
-    let (mut sender, conn) = http1_client::Builder::new()
-        .preserve_header_case(true)
-        .title_case_headers(true)
-        .handshake(io)
-        .await?;
+    // Requests without a body (e.g. the health endpoint of the instance) are not JSON-RPC calls.
+    let method = request.as_ref().map(|request| request.method.clone());
+    if (request.is_some() || !body.is_empty())
+        && !is_method_allowed(&state, &proxy_req.consumer.tier, method.as_deref()).await
+    {
+        let (status, error) = match &request {
+            Some(request) => {
+                let mut error = request.error(METHOD_NOT_ALLOWED, METHOD_NOT_ALLOWED_MESSAGE, None);
+                if is_v5 {
+                    error = translate::response(error);
+                }
+                (StatusCode::FORBIDDEN, error)
+            }
+            None => (StatusCode::BAD_REQUEST, invalid_request()),
+        };
+        return Ok(Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "application/json")
+            .body(full(error.to_string()))
+            .unwrap());
+    }
 
-    tokio::task::spawn(async move {
-        if let Err(err) = conn.await {
-            println!("Connection failed: {:?}", err);
+    let rate_limit = match try_limiter(state.clone(), &proxy_req.consumer, method.as_deref()).await
+    {
+        Ok(rate_limit) => rate_limit,
+        Err(err) => return Ok(limiter_error_response(err)),
+    };
+
+    if let Err(err) = request_bandwidth(&state, &proxy_req.consumer, body.len()).await {
+        return Ok(limiter_error_response(err));
+    }
+    if let Err(err) = request_quota(&state, &proxy_req.consumer).await {
+        return Ok(limiter_error_response(err));
+    }
+
+    let mut response =
+        forward_http(parts, body, request, proxy_req, upstream, state.clone()).await?;
+    if let Some(rate_limit) = rate_limit {
+        insert_rate_limit_headers(&mut response, &rate_limit);
+    }
+    if !is_v5 && !has_bandwidth_limits(&state, &proxy_req.consumer).await {
+        return Ok(response);
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let mut body = match body.collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(err) => {
+            error!(error = err.to_string(), "fail to read the instance body");
+            return Ok(bad_gateway());
+        }
+    };
+    if is_v5 {
+        if let Ok(value) = serde_json::from_slice::<Value>(&body) {
+            parts.headers.remove(CONTENT_LENGTH);
+            body = translate::response(value).to_string().into();
         }
+    }
+    response_bandwidth(&state, &proxy_req.consumer, body.len()).await;
+    Ok(Response::from_parts(parts, full(body)))
+}
+
+/// Responses are buffered to be charged only when the tier limits the bandwidth, otherwise
+/// they are streamed to the client.
+async fn has_bandwidth_limits(state: &State, consumer: &Consumer) -> bool {
+    state
+        .tiers
+        .read()
+        .await
+        .get(&consumer.tier)
+        .is_some_and(|tier| !tier.bandwidth.is_empty() || !tier.byte_quotas.is_empty())
+}
+
+async fn forward_http(
+    parts: Parts,
+    body: Bytes,
+    request: Option<RpcRequest>,
+    proxy_req: &ProxyRequest,
+    upstream: UpstreamLease,
+    state: Arc<State>,
+) -> Result<ProxyResponse, hyper::Error> {
+    let cache_key = request.as_ref().and_then(|request| {
+        state
+            .cache
+            .key(&proxy_req.consumer.network, &proxy_req.version, request)
     });
+    let mut flight_guard = None;
+    // Recorded before the request is sent, a response may be computed before the tip moves.
+    let mut position = Default::default();
+    if let (Some(request), Some(key)) = (&request, &cache_key) {
+        position = state.cache.position(key).await;
+        if let Some(response) = state.cache.get(key, request).await {
+            state
+                .metrics
+                .count_cache_request(proxy_req, &request.method, CACHE_HIT);
+            return Ok(json_response(response));
+        }
+
+        match state.cache.join(key) {
+            Flight::Leader(guard) => {
+                state
+                    .metrics
+                    .count_cache_request(proxy_req, &request.method, CACHE_MISS);
+                flight_guard = Some(guard);
+            }
+            Flight::Follower(receiver) => {
+                state
+                    .metrics
+                    .count_cache_request(proxy_req, &request.method, CACHE_COALESCED);
+                // When the leader fails or is too slow, the request is sent to the instance.
+                if let Ok(Ok(response)) = tokio::time::timeout(COALESCE_TIMEOUT, receiver).await {
+                    if answers(request, &response) {
+                        return Ok(json_response(request.with_id(response)));
+                    }
+                }
+            }
+        }
+    }
+
+    let mut hyper_req = Request::from_parts(parts, Full::new(body));
+    proxy_req.forwarded.insert_headers(hyper_req.headers_mut());
+    // The instances only speak HTTP/1.1, whatever the version of the client connection.
+    *hyper_req.version_mut() = Version::HTTP_11;
+    let path = hyper_req
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    *hyper_req.uri_mut() = match format!("http://{}{}", upstream.addr(), path).parse() {
+        Ok(uri) => uri,
+        Err(err) => {
+            error!(error = err.to_string(), "invalid upstream uri");
+            return Ok(bad_gateway());
+        }
+    };
+    // The client host may carry the api key prefix, the instance gets its own address instead.
+    match HeaderValue::from_str(upstream.addr()) {
+        Ok(host) => {
+            hyper_req.headers_mut().insert(HOST, host);
+        }
+        Err(err) => {
+            error!(error = err.to_string(), "invalid upstream host");
+            return Ok(bad_gateway());
+        }
+    }
+
+    let resp = match state.http_client.request(hyper_req).await {
+        Ok(resp) => resp,
+        Err(err) => {
+            error!(
+                error = err.to_string(),
+                upstream = upstream.addr(),
+                connect = err.is_connect(),
+                "fail to send request to the instance"
+            );
+            return Ok(bad_gateway());
+        }
+    };
+
+    if let (Some(request), Some(key)) = (request, cache_key) {
+        if resp.status() == StatusCode::OK {
+            let (parts, body) = resp.into_parts();
+            let body = match body.collect().await {
+                Ok(body) => body.to_bytes(),
+                Err(err) => {
+                    error!(error = err.to_string(), "fail to read the instance body");
+                    return Ok(bad_gateway());
+                }
+            };
+            if let Ok(value) = serde_json::from_slice::<Value>(&body) {
+                if answers(&request, &value) {
+                    if let Some(guard) = flight_guard {
+                        guard.complete(&value);
+                    }
+                    state
+                        .cache
+                        .insert(key, &request.method, value, position)
+                        .await;
+                }
+            }
+            return Ok(Response::from_parts(parts, full(body)));
+        }
+    }
 
-    let resp = sender.send_request(hyper_req).await?;
     Ok(resp.map(|b| b.boxed()))
 }
 
+fn limiter_error_response(err: LimiterError) -> ProxyResponse {
+    match err {
+        LimiterError::PortDeleted => Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(full("Unauthorized"))
+            .unwrap(),
+        LimiterError::InvalidTier => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(full(
+                "Invalid tier value. Contact support team for more information.",
+            ))
+            .unwrap(),
+        LimiterError::RateLimited {
+            rate_limit,
+            retry_after,
+        } => {
+            let mut response = Response::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .header(RETRY_AFTER, retry_after.as_secs().max(1))
+                .body(full("Rate limit exceeded"))
+                .unwrap();
+            insert_rate_limit_headers(&mut response, &rate_limit);
+            response
+        }
+        LimiterError::ByteQuotaExceeded { retry_after } => Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header(RETRY_AFTER, retry_after.as_secs().max(1))
+            .body(full("Byte quota exceeded"))
+            .unwrap(),
+        // A distinct status from rate limits, the quota won't be back until the next period.
+        LimiterError::QuotaExceeded {
+            period,
+            retry_after,
+        } => Response::builder()
+            .status(StatusCode::PAYMENT_REQUIRED)
+            .header(RETRY_AFTER, retry_after.as_secs().max(1))
+            .body(full(format!("Quota exceeded for the {period} period")))
+            .unwrap(),
+    }
+}
+
+fn insert_rate_limit_headers(response: &mut ProxyResponse, rate_limit: &RateLimit) {
+    let headers = response.headers_mut();
+    headers.insert(X_RATE_LIMIT_LIMIT, rate_limit.limit.into());
+    headers.insert(X_RATE_LIMIT_REMAINING, rate_limit.remaining.into());
+    headers.insert(X_RATE_LIMIT_RESET, rate_limit.reset.as_secs().into());
+}
+
+fn bad_request() -> ProxyResponse {
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .body(full("Bad Request"))
+        .unwrap()
+}
+
+fn bad_gateway() -> ProxyResponse {
+    Response::builder()
+        .status(StatusCode::BAD_GATEWAY)
+        .body(full("Bad Gateway"))
+        .unwrap()
+}
+
+fn json_response(value: Value) -> ProxyResponse {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(full(value.to_string()))
+        .unwrap()
+}
+
+/// Takes a connection of the consumer across the proxy replicas when the leases are shared.
+/// Returns `None` when every connection of the tier is taken by the fleet.
+async fn acquire_connection_lease(
+    state: &State,
+    consumer: &Consumer,
+    max_connections: usize,
+) -> Option<Option<ConnectionLease>> {
+    let Some(leases) = &state.connection_leases else {
+        return Some(None);
+    };
+
+    match leases.acquire(consumer, max_connections).await {
+        Ok(Some(lease)) => Some(Some(lease)),
+        Ok(None) => None,
+        // Fails open, see `crate::redis`. The local limit still applies.
+        Err(err) => {
+            error!(error = err.to_string(), "fail to acquire connection lease");
+            Some(None)
+        }
+    }
+}
+
+fn connection_limit_exceeded() -> ProxyResponse {
+    Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .body(full("Connection limit exceeded"))
+        .unwrap()
+}
+
 async fn handle_websocket(
     mut hyper_req: Request<Incoming>,
     proxy_req: &ProxyRequest,
+    upstream: UpstreamLease,
+    lease: Option<ConnectionLease>,
     state: Arc<State>,
 ) -> Result<ProxyResponse, hyper::Error> {
     let headers = hyper_req.headers();
@@ -189,80 +610,26 @@ async fn handle_websocket(
 
     let proxy_req = proxy_req.clone();
     let state = state.clone();
+    // Taken before the upgrade, the connection of the client is no longer tracked afterwards.
+    let in_flight = state.shutdown.track();
 
     tokio::task::spawn(async move {
+        let _in_flight = in_flight;
         match hyper::upgrade::on(&mut hyper_req).await {
             Ok(upgraded) => {
                 let upgraded = TokioIo::new(upgraded);
                 let client_stream =
                     WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
-                let (client_outgoing, mut client_incoming) = client_stream.split();
-
-                let url =
-                    Url::parse(&format!("ws://{}{}", proxy_req.instance, hyper_req.uri())).unwrap();
-                let connection_result = connect_async(url).await;
-                if let Err(err) = connection_result {
-                    error!(error = err.to_string(), "fail to connect to the instance");
-                    return;
-                }
-                let (instance_stream, _) = connection_result.unwrap();
-                let (mut instance_outgoing, instance_incoming) = instance_stream.split();
-
-                state.metrics.inc_ws_total_connection(&proxy_req);
-                proxy_req.consumer.inc_connections(state.clone()).await;
-
-                let active_connections = proxy_req
-                    .consumer
-                    .get_active_connections(state.clone())
-                    .await;
-                info!(
-                    consumer = proxy_req.consumer.to_string(),
-                    active_connections, "client connected"
-                );
-
-                let client_in = async {
-                    while let Some(result) = client_incoming.next().await {
-                        match result {
-                            Ok(data) => {
-                                if let Err(err) = limiter(state.clone(), &proxy_req.consumer).await
-                                {
-                                    error!(error = err.to_string(), "Failed to run limiter.");
-                                    break;
-                                };
-                                if let Err(err) = instance_outgoing.send(data).await {
-                                    error!(
-                                        error = err.to_string(),
-                                        "fail to send data to instance"
-                                    );
-                                    break;
-                                }
-                            }
-                            Err(err) => {
-                                error!(error = err.to_string(), "stream client incoming");
-                                break;
-                            }
-                        }
-                    }
-                };
-                pin!(client_in);
-
-                let instance_in = instance_incoming
-                    .inspect_ok(|_| state.metrics.count_ws_total_frame(&proxy_req))
-                    .forward(client_outgoing);
-
-                select(client_in, instance_in).await;
-
-                state.metrics.dec_ws_total_connection(&proxy_req);
-                proxy_req.consumer.dec_connections(state.clone()).await;
 
-                let active_connections = proxy_req
-                    .consumer
-                    .get_active_connections(state.clone())
-                    .await;
-                info!(
-                    consumer = proxy_req.consumer.to_string(),
-                    active_connections, "client disconnected"
-                );
+                websocket::session(
+                    client_stream,
+                    proxy_req,
+                    upstream,
+                    hyper_req.uri().clone(),
+                    state,
+                )
+                .await;
+                drop(lease);
             }
             Err(err) => {
                 error!(error = err.to_string(), "upgrade error");
@@ -271,8 +638,13 @@ async fn handle_websocket(
     });
 
     let mut res = Response::new(BoxBody::default());
-    *res.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
     *res.version_mut() = version;
+    // An extended CONNECT is accepted with a 200, the stream then carries the websocket frames.
+    if version == Version::HTTP_2 {
+        return Ok(res);
+    }
+
+    *res.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
     res.headers_mut().append(CONNECTION, upgrade);
     res.headers_mut().append(UPGRADE, websocket);
     res.headers_mut()
@@ -281,7 +653,16 @@ async fn handle_websocket(
     Ok(res)
 }
 
-async fn handle_healthz() -> Result<ProxyResponse, hyper::Error> {
+pub async fn handle_healthz(state: &State) -> Result<ProxyResponse, hyper::Error> {
+    // Not ready once shutting down, so the load balancer stops sending new connections before
+    // the listeners close.
+    if !state.shutdown.is_running() {
+        return Ok(Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body(full("draining"))
+            .unwrap());
+    }
+
     Ok(Response::builder()
         .status(StatusCode::OK)
         .body(full("pong"))
@@ -307,12 +688,23 @@ pub struct ProxyRequest {
     pub namespace: String,
     pub host: String,
     pub instance: String,
+    pub version: String,
     pub consumer: Consumer,
     pub protocol: Protocol,
+    pub forwarded: Forwarded,
 }
 impl ProxyRequest {
-    pub async fn new(hyper_req: &mut Request<Incoming>, state: &State) -> Option<Self> {
-        let mut host = get_header(hyper_req, HOST.as_str())?;
+    pub async fn new(
+        hyper_req: &mut Request<Incoming>,
+        connection: &Connection,
+        state: &State,
+    ) -> Option<Self> {
+        // Behind an ingress the host the client connected to is in `X-Forwarded-Host`.
+        let mut host = get_trusted_header(hyper_req, connection, X_FORWARDED_HOST)
+            .and_then(|host| host.split(',').next().map(|host| host.trim().to_string()))
+            .or_else(|| get_header(hyper_req, HOST.as_str()))
+            // HTTP/2 requests carry the host in the `:authority` pseudo header.
+            .or_else(|| hyper_req.uri().authority().map(|a| a.to_string()))?;
         let host_regex = host.clone();
 
         let captures = state.host_regex.captures(&host_regex)?;
@@ -328,54 +720,290 @@ impl ProxyRequest {
                 Protocol::Http
             })
             .unwrap_or(Protocol::Http);
+        // HTTP/2 websockets are opened with an extended CONNECT request (RFC 8441).
+        let protocol = match hyper_req.extensions().get::<hyper::ext::Protocol>() {
+            Some(ext) if hyper_req.method() == Method::CONNECT && ext.as_str() == "websocket" => {
+                Protocol::Websocket
+            }
+            _ => protocol,
+        };
 
-        if let Some(key) = captures.get(1) {
-            let key = key.as_str();
-            hyper_req
-                .headers_mut()
-                .insert(DMTR_API_KEY, HeaderValue::from_str(key).unwrap());
+        let host_key = captures.get(1).map(|key| key.as_str().to_string());
+        if let Some(key) = &host_key {
             host = host.replace(&format!("{key}."), "");
         }
 
-        let token = get_header(hyper_req, DMTR_API_KEY).unwrap_or_default();
+        let forwarded = Forwarded::new(hyper_req, connection, &host);
+
+        let token = take_api_key(hyper_req, host_key).unwrap_or_default();
         let consumer = state.get_consumer(&token).await?;
-        let instance = format!(
-            "ogmios-{}-{}.{}:{}",
-            consumer.network, consumer.version, state.config.ogmios_dns, state.config.ogmios_port
-        );
+        let version = state.upstream_version(&consumer.version).to_string();
+        let instance = state.instance_addr(&consumer.network, &version);
 
         Some(Self {
             namespace,
             instance,
+            version,
             consumer,
             protocol,
             host,
+            forwarded,
         })
     }
+
+    /// Whether the frames are translated between the port version and the instance version.
+    pub fn translate(&self) -> bool {
+        self.version != self.consumer.version
+    }
+}
+
+/// Takes the API key out of the request, so it isn't forwarded to the instance. The key is
+/// looked up in the host prefix, the `dmtr-api-key` header, an `Authorization: Bearer` header,
+/// the `apiKey` query parameter and the `/dmtr_.../` path prefix, in that order.
+fn take_api_key(hyper_req: &mut Request<Incoming>, host_key: Option<String>) -> Option<String> {
+    let header_key = hyper_req
+        .headers_mut()
+        .remove(DMTR_API_KEY)
+        .and_then(|value| value.to_str().ok().map(|key| key.to_string()));
+
+    let bearer_key = get_header(hyper_req, AUTHORIZATION.as_str()).and_then(|value| {
+        let (scheme, key) = value.split_once(' ')?;
+        scheme
+            .eq_ignore_ascii_case("bearer")
+            .then(|| key.trim().to_string())
+    });
+    if bearer_key.is_some() {
+        hyper_req.headers_mut().remove(AUTHORIZATION);
+    }
+
+    let (path_and_query, query_key, path_key) = strip_uri_api_key(hyper_req.uri());
+    if query_key.is_some() || path_key.is_some() {
+        let mut parts = hyper_req.uri().clone().into_parts();
+        parts.path_and_query = path_and_query.parse().ok();
+        if let Ok(uri) = Uri::from_parts(parts) {
+            *hyper_req.uri_mut() = uri;
+        }
+    }
+
+    host_key
+        .or(header_key)
+        .or(bearer_key)
+        .or(query_key)
+        .or(path_key)
 }
 
-fn build_tls_acceptor(state: &State) -> Result<TlsAcceptor, Box<dyn Error>> {
-    let certs = load_certs(&state.config.ssl_crt_path)?;
+/// Path and query without the API key, with the keys of the `apiKey` query parameter and of the
+/// path prefix.
+fn strip_uri_api_key(uri: &Uri) -> (String, Option<String>, Option<String>) {
+    let segments = uri
+        .path()
+        .strip_prefix('/')
+        .map(|path| path.split_once('/').unwrap_or((path, "")));
+    let (path, path_key) = match segments {
+        Some((prefix, rest)) if prefix.starts_with(API_KEY_PATH_PREFIX) => {
+            (format!("/{rest}"), Some(prefix.to_string()))
+        }
+        _ => (uri.path().to_string(), None),
+    };
+
+    let mut query_key = None;
+    let query: Vec<&str> = uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| match pair.split_once('=') {
+            Some((API_KEY_QUERY, key)) => {
+                query_key.get_or_insert(key.to_string());
+                false
+            }
+            _ => !pair.is_empty(),
+        })
+        .collect();
+
+    let path_and_query = match query.is_empty() {
+        true => path,
+        false => format!("{path}?{}", query.join("&")),
+    };
+    (path_and_query, query_key, path_key)
+}
 
-    let key = load_private_key(&state.config.ssl_key_path)?;
+/// Client of the request, sent to the instance in the `X-Forwarded-*` headers.
+#[derive(Debug, Clone)]
+pub struct Forwarded {
+    pub client: String,
+    pub proto: String,
+    pub host: String,
+}
+impl Forwarded {
+    fn new(hyper_req: &Request<Incoming>, connection: &Connection, host: &str) -> Self {
+        let remote_ip = connection.remote_addr.ip().to_string();
+        let client = match get_trusted_header(hyper_req, connection, X_FORWARDED_FOR) {
+            Some(forwarded_for) => format!("{forwarded_for}, {remote_ip}"),
+            None => remote_ip,
+        };
+        let proto = get_trusted_header(hyper_req, connection, X_FORWARDED_PROTO).unwrap_or(
+            match connection.tls {
+                true => "https".into(),
+                false => "http".into(),
+            },
+        );
 
-    let server_config = ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(certs, key)
-        .unwrap();
+        Self {
+            client,
+            proto,
+            host: host.into(),
+        }
+    }
 
-    let tls_acceptor = TlsAcceptor::from(Arc::new(server_config));
-    Ok(tls_acceptor)
+    pub fn insert_headers(&self, headers: &mut HeaderMap) {
+        for (name, value) in [
+            (X_FORWARDED_FOR, &self.client),
+            (X_FORWARDED_PROTO, &self.proto),
+            (X_FORWARDED_HOST, &self.host),
+        ] {
+            if let Ok(value) = HeaderValue::from_str(value) {
+                headers.insert(name, value);
+            }
+        }
+    }
 }
 
-fn load_certs(path: &PathBuf) -> io::Result<Vec<CertificateDer<'static>>> {
-    let cert_file = fs::File::open(path)?;
-    let mut reader = io::BufReader::new(cert_file);
-    rustls_pemfile::certs(&mut reader).collect()
+/// Headers set by the ingress in front of plaintext listeners. On tls listeners they come from
+/// the client, so they are ignored.
+fn get_trusted_header(
+    hyper_req: &Request<Incoming>,
+    connection: &Connection,
+    key: &str,
+) -> Option<String> {
+    match connection.tls {
+        true => None,
+        false => get_header(hyper_req, key),
+    }
 }
 
-fn load_private_key(path: &PathBuf) -> io::Result<PrivateKeyDer<'static>> {
-    let key_file = fs::File::open(path)?;
-    let mut reader = io::BufReader::new(key_file);
-    rustls_pemfile::private_key(&mut reader).map(|key| key.unwrap())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ConsumerToken;
+    use futures_util::StreamExt;
+    use http_body_util::Empty;
+    use hyper::client::conn::http2;
+    use tokio::net::TcpStream;
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+    use tokio_tungstenite::tungstenite::Message;
+
+    const TOKEN: &str = "dmtr_ogmios1test";
+
+    /// State of a plaintext proxy with one port, routed to an instance that is not running.
+    async fn state() -> Arc<State> {
+        let instance = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let instance_addr = instance.local_addr().unwrap();
+        drop(instance);
+
+        for (key, value) in [
+            ("PROXY_ADDR", "127.0.0.1:0".to_string()),
+            ("PROXY_LISTENER_MODE", "plaintext".into()),
+            ("PROXY_TIERS_PATH", "/nonexistent/tiers.toml".into()),
+            ("PROMETHEUS_ADDR", "127.0.0.1:0".into()),
+            ("OGMIOS_PORT", "1337".into()),
+            ("OGMIOS_DNS", "local".into()),
+            ("OGMIOS_UPSTREAMS", format!("mainnet-6={instance_addr}")),
+        ] {
+            std::env::set_var(key, value);
+        }
+        let state = State::try_new().unwrap();
+
+        let consumer = Consumer {
+            namespace: "ns".into(),
+            port_name: "port".into(),
+            tier: "0".into(),
+            id: "ns.port".into(),
+            network: "mainnet".into(),
+            version: "6".into(),
+            active_connections: 0,
+        };
+        let tier = serde_json::json!({ "name": "0", "rates": [], "max_connections": 5 });
+        state
+            .tiers
+            .write()
+            .await
+            .insert("0".into(), serde_json::from_value(tier).unwrap());
+        state.tokens.write().await.insert(
+            TOKEN.into(),
+            ConsumerToken {
+                consumer: consumer.id.clone(),
+                not_after: None,
+            },
+        );
+        state
+            .consumers
+            .write()
+            .await
+            .insert(consumer.id.clone(), consumer);
+        Arc::new(state)
+    }
+
+    #[tokio::test]
+    async fn accepts_websockets_over_http2_extended_connect() {
+        let state = state().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let phase = state.shutdown.subscribe();
+        tokio::spawn(async move {
+            let (stream, remote_addr) = listener.accept().await.unwrap();
+            let connection = Connection {
+                remote_addr,
+                tls: false,
+            };
+            serve(TokioIo::new(stream), connection, phase, state).await;
+        });
+
+        // Plaintext listeners speak HTTP/2 with prior knowledge.
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut sender, connection) = http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+
+        // The settings of the server, enabling the extended CONNECT, arrive with the first
+        // response.
+        let request = Request::get(format!("http://{addr}/healthz"))
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.version(), Version::HTTP_2);
+
+        let mut request = Request::connect(format!("http://{TOKEN}.{addr}/"))
+            .version(Version::HTTP_2)
+            .header("sec-websocket-version", "13")
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(hyper::ext::Protocol::from_static("websocket"));
+        let mut response = sender.send_request(request).await.unwrap();
+
+        // RFC 8441 accepts the stream with a 200, a 101 is invalid over HTTP/2.
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key(SEC_WEBSOCKET_ACCEPT));
+
+        // The stream carries the websocket frames: the instance is down, so the session is
+        // closed right away.
+        let upgraded = hyper::upgrade::on(&mut response).await.unwrap();
+        let mut client =
+            WebSocketStream::from_raw_socket(TokioIo::new(upgraded), Role::Client, None).await;
+        let message = tokio::time::timeout(Duration::from_secs(5), client.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        match message {
+            Message::Close(Some(frame)) => {
+                assert_eq!(frame.code, CloseCode::Again);
+                assert_eq!(frame.reason, websocket::CLOSE_REASON_UPSTREAM_UNAVAILABLE);
+            }
+            message => panic!("expected a close frame, got {message:?}"),
+        }
+    }
 }
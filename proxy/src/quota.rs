@@ -0,0 +1,464 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{fmt::Display, fs};
+use tracing::{error, info, instrument, warn};
+
+use crate::config::Config;
+use crate::limiter::{LimiterError, REDIS_PREFIX};
+use crate::redis::{Cmd, RedisClient};
+use crate::tiers::{Tier, TierQuota};
+use crate::{Consumer, State};
+
+const DAY_MILLIS: u64 = 24 * 60 * 60 * 1000;
+
+/// Calendar period of a quota, in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaPeriod {
+    Daily,
+    Monthly,
+}
+impl QuotaPeriod {
+    /// Index of the period containing the time and the time when the next period starts, both
+    /// in milliseconds since the unix epoch.
+    fn window(&self, now: u64) -> (u64, u64) {
+        let days = now / DAY_MILLIS;
+        match self {
+            QuotaPeriod::Daily => (days, (days + 1) * DAY_MILLIS),
+            QuotaPeriod::Monthly => {
+                let (year, month, _) = civil_from_days(days as i64);
+                let (next_year, next_month) = match month {
+                    12 => (year + 1, 1),
+                    month => (year, month + 1),
+                };
+                let index = (year * 12 + month as i64 - 1) as u64;
+                let reset = days_from_civil(next_year, next_month, 1) as u64 * DAY_MILLIS;
+                (index, reset)
+            }
+        }
+    }
+
+    /// Time until the next period starts.
+    fn retry_after(&self, now: u64) -> Duration {
+        let (_, reset) = self.window(now);
+        Duration::from_millis(reset - now)
+    }
+}
+impl Display for QuotaPeriod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuotaPeriod::Daily => f.write_str("daily"),
+            QuotaPeriod::Monthly => f.write_str("monthly"),
+        }
+    }
+}
+
+// Conversions between days since the unix epoch and the proleptic gregorian calendar, from
+// http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[async_trait]
+pub trait QuotaStore: Send + Sync {
+    /// Counts one request unless the counter already reached the limit. Returns `false` when
+    /// the quota is exhausted. The counter is dropped once `expires_at` (milliseconds since the
+    /// unix epoch) is reached.
+    async fn charge(
+        &self,
+        key: &str,
+        limit: u64,
+        expires_at: u64,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>>;
+
+    /// Drops the counters of past periods, for stores that don't expire them on their own.
+    fn prune(&self) {}
+
+    /// Persists the counters, for stores that don't persist them on their own.
+    fn save(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(())
+    }
+}
+
+/// Shared store backend when `PROXY_REDIS_URL` is set, so every proxy replica counts against
+/// the same quotas. Otherwise the counters are kept in memory and saved to
+/// `PROXY_QUOTA_SNAPSHOT_PATH` when set.
+pub fn build_store(config: &Config, redis: Option<Arc<RedisClient>>) -> Box<dyn QuotaStore> {
+    match redis {
+        Some(client) => Box::new(RedisQuotaStore::new(client)),
+        None => Box::new(MemoryQuotaStore::load(
+            config.proxy_quota_snapshot_path.clone(),
+        )),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Counter {
+    count: u64,
+    expires_at: u64,
+}
+
+pub struct MemoryQuotaStore {
+    counters: Mutex<HashMap<String, Counter>>,
+    snapshot_path: Option<PathBuf>,
+}
+impl MemoryQuotaStore {
+    /// Starts from the snapshot when there is one, a missing or invalid snapshot starts empty.
+    pub fn load(snapshot_path: Option<PathBuf>) -> Self {
+        let counters = snapshot_path
+            .as_ref()
+            .filter(|path| path.exists())
+            .and_then(|path| match fs::read(path) {
+                Ok(contents) => match serde_json::from_slice(&contents) {
+                    Ok(counters) => Some(counters),
+                    Err(err) => {
+                        warn!(
+                            error = err.to_string(),
+                            "invalid quota snapshot, ignoring it"
+                        );
+                        None
+                    }
+                },
+                Err(err) => {
+                    warn!(error = err.to_string(), "fail to read quota snapshot");
+                    None
+                }
+            })
+            .unwrap_or_default();
+
+        Self {
+            counters: Mutex::new(counters),
+            snapshot_path,
+        }
+    }
+}
+#[async_trait]
+impl QuotaStore for MemoryQuotaStore {
+    async fn charge(
+        &self,
+        key: &str,
+        limit: u64,
+        expires_at: u64,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let mut counters = self.counters.lock().unwrap();
+        let counter = counters.entry(key.to_string()).or_insert(Counter {
+            count: 0,
+            expires_at,
+        });
+        if counter.expires_at <= now_millis() {
+            *counter = Counter {
+                count: 0,
+                expires_at,
+            };
+        }
+
+        if counter.count >= limit {
+            return Ok(false);
+        }
+        counter.count += 1;
+        Ok(true)
+    }
+
+    fn prune(&self) {
+        let now = now_millis();
+        self.counters
+            .lock()
+            .unwrap()
+            .retain(|_, counter| counter.expires_at > now);
+    }
+
+    /// Writes the counters still running to the snapshot. The file is replaced at once, so a
+    /// crash while saving keeps the previous snapshot.
+    fn save(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let Some(path) = &self.snapshot_path else {
+            return Ok(());
+        };
+
+        self.prune();
+        let contents = serde_json::to_vec(&*self.counters.lock().unwrap())?;
+
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, contents)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+/// Counters kept in a Redis compatible store. While the store fails, the replica counts on its
+/// own in memory.
+pub struct RedisQuotaStore {
+    client: Arc<RedisClient>,
+    fallback: MemoryQuotaStore,
+}
+impl RedisQuotaStore {
+    pub fn new(client: Arc<RedisClient>) -> Self {
+        Self {
+            client,
+            fallback: MemoryQuotaStore::load(None),
+        }
+    }
+
+    async fn try_charge(
+        &self,
+        key: &str,
+        limit: u64,
+        expires_at: u64,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let replies = self
+            .client
+            .pipeline(&[
+                Cmd::new("INCR").arg(key),
+                Cmd::new("PEXPIREAT").arg(key).arg(expires_at),
+            ])
+            .await?;
+        let count = replies[0]
+            .as_integer()
+            .ok_or("INCR must reply an integer")?;
+
+        if count > limit as i64 {
+            self.client.pipeline(&[Cmd::new("DECR").arg(key)]).await?;
+            return Ok(false);
+        }
+        Ok(true)
+    }
+}
+#[async_trait]
+impl QuotaStore for RedisQuotaStore {
+    async fn charge(
+        &self,
+        key: &str,
+        limit: u64,
+        expires_at: u64,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        match self.try_charge(key, limit, expires_at).await {
+            Ok(charged) => Ok(charged),
+            // Fails open, see `crate::redis`.
+            Err(err) => {
+                error!(error = err.to_string(), "fail to charge quota in redis");
+                self.fallback.charge(key, limit, expires_at).await
+            }
+        }
+    }
+
+    fn prune(&self) {
+        self.fallback.prune();
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Counts the request against every quota of the tier. Counters are keyed by the port and the
+/// period, so they survive tier reloads and changes of the quota limit.
+pub async fn quota(state: &State, consumer: &Consumer, tier: &Tier) -> Result<(), LimiterError> {
+    let now = now_millis();
+    for TierQuota { period, limit } in &tier.quotas {
+        let (window, reset) = period.window(now);
+        let key = format!("{REDIS_PREFIX}:quota:{consumer}:{period}:{window}");
+
+        match state.quotas.charge(&key, *limit, reset).await {
+            Ok(true) => {}
+            Ok(false) => {
+                return Err(LimiterError::QuotaExceeded {
+                    period: *period,
+                    retry_after: period.retry_after(now),
+                })
+            }
+            // Fails open, see `crate::redis`.
+            Err(err) => error!(error = err.to_string(), "fail to charge quota"),
+        }
+    }
+    Ok(())
+}
+
+#[instrument("quota background service", skip_all)]
+pub fn start(state: Arc<State>) {
+    tokio::spawn(async move {
+        info!("quota: pruning counters and saving snapshots");
+
+        // Counters of past periods are dropped whether they are saved or not, otherwise every
+        // port would keep one counter per period forever.
+        loop {
+            tokio::time::sleep(state.config.proxy_quota_snapshot_interval).await;
+
+            state.quotas.prune();
+            if let Err(err) = state.quotas.save() {
+                error!(error = err.to_string(), "fail to save quota snapshot");
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redis::stand_in;
+
+    // 2024-01-31T12:00:00Z and the following midnights.
+    const JAN_31_NOON: u64 = 1706702400000;
+    const FEB_1: u64 = 1706745600000;
+    const MAR_1: u64 = 1709251200000;
+
+    #[test]
+    fn converts_civil_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(civil_from_days(19782), (2024, 2, 29));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        for days in (-800_000..800_000).step_by(997) {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn daily_windows() {
+        assert_eq!(QuotaPeriod::Daily.window(0), (0, DAY_MILLIS));
+        assert_eq!(QuotaPeriod::Daily.window(JAN_31_NOON), (19753, FEB_1));
+        assert_eq!(
+            QuotaPeriod::Daily.window(FEB_1),
+            (19754, FEB_1 + DAY_MILLIS)
+        );
+        assert_eq!(
+            QuotaPeriod::Daily.retry_after(JAN_31_NOON),
+            Duration::from_secs(12 * 60 * 60)
+        );
+    }
+
+    #[test]
+    fn monthly_windows_roll_over() {
+        // The last day of a month resets on the first day of the next one.
+        assert_eq!(QuotaPeriod::Monthly.window(JAN_31_NOON), (2024 * 12, FEB_1));
+        assert_eq!(
+            QuotaPeriod::Monthly.retry_after(JAN_31_NOON),
+            Duration::from_secs(12 * 60 * 60)
+        );
+
+        // Leap years.
+        assert_eq!(QuotaPeriod::Monthly.window(FEB_1), (2024 * 12 + 1, MAR_1));
+        assert_eq!(
+            QuotaPeriod::Monthly.retry_after(MAR_1 - 1000),
+            Duration::from_secs(1)
+        );
+        // 2023-02-28T10:00:00Z resets on 2023-03-01.
+        assert_eq!(
+            QuotaPeriod::Monthly.retry_after(1677578400000),
+            Duration::from_secs(14 * 60 * 60)
+        );
+
+        // December resets on January of the next year, 2023-12-31T23:00:00Z.
+        assert_eq!(
+            QuotaPeriod::Monthly.window(1704063600000),
+            (2023 * 12 + 11, 1704067200000)
+        );
+        assert_eq!(
+            QuotaPeriod::Monthly.retry_after(1704063600000),
+            Duration::from_secs(60 * 60)
+        );
+    }
+
+    #[tokio::test]
+    async fn memory_store_charges_until_the_limit() {
+        let store = MemoryQuotaStore::load(None);
+        let expires_at = now_millis() + 60_000;
+
+        assert!(store.charge("a", 2, expires_at).await.unwrap());
+        assert!(store.charge("a", 2, expires_at).await.unwrap());
+        assert!(!store.charge("a", 2, expires_at).await.unwrap());
+        assert!(store.charge("b", 2, expires_at).await.unwrap());
+
+        // A counter of a past period starts over.
+        store
+            .counters
+            .lock()
+            .unwrap()
+            .get_mut("a")
+            .unwrap()
+            .expires_at = 1;
+        assert!(store.charge("a", 2, expires_at).await.unwrap());
+        assert_eq!(store.counters.lock().unwrap()["a"].count, 1);
+    }
+
+    #[tokio::test]
+    async fn memory_store_prunes_without_snapshot() {
+        let store = MemoryQuotaStore::load(None);
+        assert!(store.charge("past", 1, 1).await.unwrap());
+        assert!(store
+            .charge("current", 1, now_millis() + 60_000)
+            .await
+            .unwrap());
+
+        store.prune();
+        let counters = store.counters.lock().unwrap();
+        assert!(!counters.contains_key("past"));
+        assert!(counters.contains_key("current"));
+    }
+
+    #[tokio::test]
+    async fn memory_store_loads_its_snapshot() {
+        let path = std::env::temp_dir().join(format!("quotas-{}.json", std::process::id()));
+        let expires_at = now_millis() + 60_000;
+
+        let store = MemoryQuotaStore::load(Some(path.clone()));
+        assert!(store.charge("a", 1, expires_at).await.unwrap());
+        assert!(store.charge("past", 1, 1).await.unwrap());
+        store.save().unwrap();
+
+        let store = MemoryQuotaStore::load(Some(path.clone()));
+        assert!(!store.charge("a", 1, expires_at).await.unwrap());
+        assert!(!store.counters.lock().unwrap().contains_key("past"));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn redis_store_gives_back_rejected_requests() {
+        let (url, keys) = stand_in::start().await;
+        let client = RedisClient::new(&url, Duration::from_secs(1)).unwrap();
+        let store = RedisQuotaStore::new(Arc::new(client));
+        let expires_at = now_millis() + DAY_MILLIS;
+
+        assert!(store.charge("a", 1, expires_at).await.unwrap());
+        assert!(!store.charge("a", 1, expires_at).await.unwrap());
+        assert!(!store.charge("a", 1, expires_at).await.unwrap());
+        assert_eq!(keys.lock().unwrap()["a"], stand_in::Entry::Integer(1));
+    }
+
+    #[tokio::test]
+    async fn redis_store_counts_in_memory_while_redis_fails() {
+        let url = stand_in::unreachable().await;
+        let client = RedisClient::new(&url, Duration::from_secs(1)).unwrap();
+        let store = RedisQuotaStore::new(Arc::new(client));
+        let expires_at = now_millis() + DAY_MILLIS;
+
+        assert!(store.charge("a", 1, expires_at).await.unwrap());
+        assert!(!store.charge("a", 1, expires_at).await.unwrap());
+    }
+}
@@ -0,0 +1,170 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tracing::{error, info, instrument};
+
+use crate::State;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+// Time for the sessions to flush their close frames, see the close timeout of the sessions.
+const CLOSE_GRACE: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    Running,
+    /// `/healthz` answers 503 so the load balancer stops routing to the proxy, which keeps
+    /// accepting connections meanwhile.
+    Unready,
+    /// New connections are refused while the live sessions keep being forwarded.
+    Draining,
+    /// The sessions left after the drain deadline are closed.
+    Closing,
+}
+
+pub struct Shutdown {
+    phase: watch::Sender<Phase>,
+    /// Client connections and websocket sessions still open.
+    in_flight: Arc<AtomicUsize>,
+}
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            phase: watch::Sender::new(Phase::Running),
+            in_flight: Default::default(),
+        }
+    }
+}
+impl Shutdown {
+    pub fn subscribe(&self) -> watch::Receiver<Phase> {
+        self.phase.subscribe()
+    }
+
+    pub fn is_running(&self) -> bool {
+        *self.phase.borrow() == Phase::Running
+    }
+
+    pub fn is_draining(&self) -> bool {
+        *self.phase.borrow() >= Phase::Draining
+    }
+
+    /// Tracks a client connection, from its accept, or a websocket session, from its upgrade,
+    /// until the guard is dropped. The drain waits for every guard, so neither the requests in
+    /// flight nor the websockets still connecting to the instance are cut off.
+    pub fn track(&self) -> InFlightGuard {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlightGuard(self.in_flight.clone())
+    }
+
+    fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Waits until nothing is in flight or the deadline is reached.
+    async fn wait_in_flight(&self, deadline: Instant) {
+        while self.in_flight() > 0 && Instant::now() < deadline {
+            tokio::time::sleep(POLL_INTERVAL.min(deadline - Instant::now())).await;
+        }
+    }
+}
+
+pub struct InFlightGuard(Arc<AtomicUsize>);
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Resolves once the phase is at least `Draining` (or `Closing` when `closing` is set).
+pub async fn wait(mut phase: watch::Receiver<Phase>, closing: bool) {
+    let closed = phase
+        .wait_for(|phase| match closing {
+            true => *phase == Phase::Closing,
+            false => *phase >= Phase::Draining,
+        })
+        .await
+        .is_err();
+    // The sender lives in the state, it is never dropped while the proxy runs.
+    if closed {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Waits for SIGTERM (or SIGINT), then drains the proxy. Resolves once the proxy can exit.
+#[instrument("shutdown", skip_all)]
+pub async fn start(state: Arc<State>) {
+    let (mut sigterm, mut sigint) = match (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+    ) {
+        (Ok(sigterm), Ok(sigint)) => (sigterm, sigint),
+        _ => {
+            error!("fail to listen to shutdown signals");
+            return std::future::pending().await;
+        }
+    };
+    tokio::select! {
+        _ = sigterm.recv() => {},
+        _ = sigint.recv() => {},
+    }
+
+    let shutdown = &state.shutdown;
+    info!(
+        prestop_delay = ?state.config.proxy_prestop_delay,
+        "shutdown: not ready"
+    );
+    shutdown.phase.send_replace(Phase::Unready);
+    tokio::time::sleep(state.config.proxy_prestop_delay).await;
+
+    info!(
+        in_flight = shutdown.in_flight(),
+        drain_timeout = ?state.config.proxy_drain_timeout,
+        "shutdown: draining"
+    );
+    shutdown.phase.send_replace(Phase::Draining);
+    shutdown
+        .wait_in_flight(Instant::now() + state.config.proxy_drain_timeout)
+        .await;
+
+    info!(
+        in_flight = shutdown.in_flight(),
+        "shutdown: closing sessions"
+    );
+    shutdown.phase.send_replace(Phase::Closing);
+    shutdown.wait_in_flight(Instant::now() + CLOSE_GRACE).await;
+
+    if let Err(err) = state.quotas.save() {
+        error!(error = err.to_string(), "fail to save quota snapshot");
+    }
+    info!("shutdown: done");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn waits_for_every_guard() {
+        let shutdown = Shutdown::default();
+        let connection = shutdown.track();
+        let session = shutdown.track();
+        assert_eq!(shutdown.in_flight(), 2);
+
+        drop(connection);
+        let started = Instant::now();
+        shutdown
+            .wait_in_flight(started + Duration::from_millis(50))
+            .await;
+        assert!(started.elapsed() >= Duration::from_millis(50));
+
+        drop(session);
+        let started = Instant::now();
+        shutdown
+            .wait_in_flight(started + Duration::from_secs(5))
+            .await;
+        assert!(started.elapsed() < Duration::from_millis(50));
+        assert_eq!(shutdown.in_flight(), 0);
+    }
+}
@@ -2,10 +2,11 @@ use notify::{Event, PollWatcher, RecursiveMode, Watcher};
 use regex::Regex;
 use serde::{Deserialize, Deserializer};
 use serde_json::Value;
-use std::{error::Error, fs, sync::Arc, time::Duration};
+use std::{collections::HashMap, error::Error, fs, sync::Arc, time::Duration};
 use tokio::runtime::{Handle, Runtime};
 use tracing::{error, info, instrument, warn};
 
+use crate::quota::QuotaPeriod;
 use crate::State;
 
 #[derive(Debug, Clone, Deserialize)]
@@ -13,18 +14,150 @@ pub struct Tier {
     pub name: String,
     pub rates: Vec<TierRate>,
     pub max_connections: usize,
+    #[serde(default)]
+    pub allowed_methods: Vec<String>,
+    #[serde(default)]
+    pub denied_methods: Vec<String>,
+    #[serde(default)]
+    pub on_limit: LimitMode,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub max_wait: Duration,
+    #[serde(default)]
+    pub bandwidth: Vec<TierBytes>,
+    #[serde(default)]
+    pub byte_quotas: Vec<TierBytes>,
+    #[serde(default)]
+    pub quotas: Vec<TierQuota>,
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub idle_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub max_session_duration: Option<Duration>,
 }
+impl Tier {
+    /// Denied patterns take precedence over allowed ones and an empty allow list means every
+    /// method is allowed. Patterns accept `*` as wildcard, e.g. `queryLedgerState/*`.
+    pub fn is_method_allowed(&self, method: &str) -> bool {
+        if self
+            .denied_methods
+            .iter()
+            .any(|pattern| method_matches(pattern, method))
+        {
+            return false;
+        }
+
+        self.allowed_methods.is_empty()
+            || self
+                .allowed_methods
+                .iter()
+                .any(|pattern| method_matches(pattern, method))
+    }
+}
+
+/// What happens to a websocket message when the rates of the tier are exhausted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitMode {
+    /// The message waits until there are enough tokens.
+    #[default]
+    Queue,
+    /// The message is answered with an error when the tokens aren't available within the
+    /// tier `max_wait`.
+    Reject,
+}
+
+/// Messages that couldn't be parsed have no method. The instance may still read one from them
+/// (e.g. with duplicate keys), so they are only forwarded when the tier doesn't restrict methods.
+pub async fn is_method_allowed(state: &State, tier: &str, method: Option<&str>) -> bool {
+    // An unknown tier is handled by the limiter, which closes the connection.
+    match state.tiers.read().await.get(tier) {
+        Some(tier) => match method {
+            Some(method) => tier.is_method_allowed(method),
+            None => tier.allowed_methods.is_empty() && tier.denied_methods.is_empty(),
+        },
+        None => true,
+    }
+}
+
+fn method_matches(pattern: &str, method: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    if !method.starts_with(first) {
+        return false;
+    }
+
+    let mut rest = &method[first.len()..];
+    let mut parts = parts.peekable();
+    if parts.peek().is_none() {
+        return rest.is_empty();
+    }
+
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+
+    true
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct TierRate {
     pub limit: usize,
     #[serde(deserialize_with = "deserialize_duration")]
     pub interval: Duration,
+    #[serde(default = "default_cost")]
+    pub default_cost: usize,
+    #[serde(default)]
+    pub costs: HashMap<String, usize>,
+}
+impl TierRate {
+    /// Tokens charged for a method. An exact method name wins over patterns, and when several
+    /// patterns match, the longest (most specific) one is used.
+    pub fn cost(&self, method: Option<&str>) -> usize {
+        let Some(method) = method else {
+            return self.default_cost;
+        };
+
+        if let Some(cost) = self.costs.get(method) {
+            return *cost;
+        }
+
+        self.costs
+            .iter()
+            .filter(|(pattern, _)| method_matches(pattern, method))
+            .max_by_key(|(pattern, _)| pattern.len())
+            .map(|(_, cost)| *cost)
+            .unwrap_or(self.default_cost)
+    }
+}
+
+/// Payload bytes allowed per interval, counted in both directions.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TierBytes {
+    pub limit: usize,
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub interval: Duration,
+}
+
+/// Requests allowed per calendar period, e.g. a monthly plan.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TierQuota {
+    pub period: QuotaPeriod,
+    pub limit: u64,
+}
+
+fn default_cost() -> usize {
+    1
 }
 pub fn deserialize_duration<'de, D: Deserializer<'de>>(
     deserializer: D,
 ) -> Result<Duration, D::Error> {
     let value: String = Deserialize::deserialize(deserializer)?;
-    let regex = Regex::new(r"([\d]+)([\w])").unwrap();
+    let regex = Regex::new(r"([\d]+)([\w]+)").unwrap();
     let captures = regex.captures(&value);
     if captures.is_none() {
         return Err(<D::Error as serde::de::Error>::custom(
@@ -37,6 +170,7 @@ pub fn deserialize_duration<'de, D: Deserializer<'de>>(
     let symbol = captures.get(2).unwrap().as_str();
 
     match symbol {
+        "ms" => Ok(Duration::from_millis(number)),
         "s" => Ok(Duration::from_secs(number)),
         "m" => Ok(Duration::from_secs(number * 60)),
         "h" => Ok(Duration::from_secs(number * 60 * 60)),
@@ -47,6 +181,12 @@ pub fn deserialize_duration<'de, D: Deserializer<'de>>(
     }
 }
 
+pub fn deserialize_optional_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    deserialize_duration(deserializer).map(Some)
+}
+
 #[instrument("tiers background service", skip_all)]
 pub fn start(state: Arc<State>) {
     tokio::spawn(async move {
@@ -115,7 +255,8 @@ async fn update_tiers(state: Arc<State>) -> Result<(), Box<dyn Error>> {
         .map(|tier| (tier.name.clone(), tier))
         .collect();
 
-    state.limiter.write().await.clear();
+    state.limiter.clear().await;
+    state.bandwidth.clear().await;
 
     Ok(())
 }
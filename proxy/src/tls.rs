@@ -0,0 +1,275 @@
+use notify::{Event, PollWatcher, RecursiveMode, Watcher};
+use rustls::crypto::ring::sign::any_supported_type;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::{fs, io};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, instrument};
+use x509_parser::extensions::GeneralName;
+use x509_parser::parse_x509_certificate;
+
+use crate::config::Config;
+use crate::metrics::Metrics;
+use crate::State;
+
+/// Server config used for new handshakes. It is replaced when the certificate files change, the
+/// connections already established keep the config of their handshake.
+pub struct Tls {
+    server_config: RwLock<Arc<ServerConfig>>,
+}
+impl Tls {
+    pub fn try_new(config: &Config, metrics: &Metrics) -> Result<Self, Box<dyn Error>> {
+        let server_config = load_server_config(config, metrics)?;
+        Ok(Self {
+            server_config: RwLock::new(Arc::new(server_config)),
+        })
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.server_config.read().unwrap().clone())
+    }
+
+    /// Loads the certificate files again. The current config is kept when they are invalid,
+    /// e.g. while only one of the files was written.
+    fn reload(&self, config: &Config, metrics: &Metrics) -> Result<(), Box<dyn Error>> {
+        let server_config = load_server_config(config, metrics)?;
+        *self.server_config.write().unwrap() = Arc::new(server_config);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct Certificate {
+    /// DNS names of the certificate, wildcards included.
+    names: Vec<String>,
+    key: Arc<CertifiedKey>,
+}
+
+/// Picks the certificate from the SNI of the handshake. Exact names are preferred over
+/// wildcards, and handshakes for names without a certificate are rejected. Handshakes without
+/// SNI (e.g. load balancer health checks) get the first certificate. A single certificate
+/// (`SSL_CRT_PATH`) is served for every name instead.
+#[derive(Debug)]
+struct Resolver {
+    certificates: Vec<Certificate>,
+    fallback: Option<Arc<CertifiedKey>>,
+}
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let Some(server_name) = client_hello.server_name() else {
+            let first = self.certificates.first();
+            return first.map(|certificate| certificate.key.clone());
+        };
+        let server_name = server_name.to_ascii_lowercase();
+
+        let exact = self
+            .certificates
+            .iter()
+            .find(|certificate| certificate.names.contains(&server_name));
+        let wildcard = || {
+            self.certificates.iter().find(|certificate| {
+                certificate
+                    .names
+                    .iter()
+                    .any(|name| matches_wildcard(name, &server_name))
+            })
+        };
+
+        match exact.or_else(wildcard) {
+            Some(certificate) => Some(certificate.key.clone()),
+            None => {
+                if self.fallback.is_none() {
+                    debug!(server_name, "no certificate for the server name");
+                }
+                self.fallback.clone()
+            }
+        }
+    }
+}
+
+/// A wildcard matches a single label, `*.demeter.run` matches `ogmios.demeter.run` but not
+/// `mainnet.ogmios.demeter.run`.
+fn matches_wildcard(name: &str, server_name: &str) -> bool {
+    let Some(zone) = name.strip_prefix("*.") else {
+        return false;
+    };
+    server_name
+        .split_once('.')
+        .is_some_and(|(label, rest)| !label.is_empty() && rest == zone)
+}
+
+fn load_server_config(config: &Config, metrics: &Metrics) -> Result<ServerConfig, Box<dyn Error>> {
+    let files = certificate_files(config)?;
+
+    let mut certificates = Vec::new();
+    let mut expiries = Vec::new();
+    for (crt_path, key_path) in &files {
+        let (certificate, not_after) = load_certificate(crt_path, key_path)?;
+        certificates.push(certificate);
+        expiries.push((crt_path.to_string_lossy().to_string(), not_after));
+    }
+
+    let fallback = match config.ssl_certs_path {
+        Some(_) => None,
+        None => certificates
+            .first()
+            .map(|certificate| certificate.key.clone()),
+    };
+    let resolver = Resolver {
+        certificates,
+        fallback,
+    };
+    metrics.set_tls_certificate_expiry(&expiries);
+
+    let mut server_config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(resolver));
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(server_config)
+}
+
+/// Paths of the certificate and key files, the pairs of `{name}.crt` and `{name}.key` files in
+/// `SSL_CERTS_PATH` or else `SSL_CRT_PATH` and `SSL_KEY_PATH`.
+fn certificate_files(config: &Config) -> Result<Vec<(PathBuf, PathBuf)>, Box<dyn Error>> {
+    let Some(certs_path) = &config.ssl_certs_path else {
+        return match (&config.ssl_crt_path, &config.ssl_key_path) {
+            (Some(crt_path), Some(key_path)) => Ok(vec![(crt_path.clone(), key_path.clone())]),
+            _ => Err("SSL_CRT_PATH and SSL_KEY_PATH or SSL_CERTS_PATH must be set".into()),
+        };
+    };
+
+    let mut files = Vec::new();
+    for entry in fs::read_dir(certs_path)? {
+        let crt_path = entry?.path();
+        if crt_path.extension().is_some_and(|ext| ext == "crt") {
+            let key_path = crt_path.with_extension("key");
+            if !key_path.exists() {
+                return Err(format!("no key for {}", crt_path.display()).into());
+            }
+            files.push((crt_path, key_path));
+        }
+    }
+    if files.is_empty() {
+        return Err(format!("no certificates in {}", certs_path.display()).into());
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Loads a certificate and its key, with the time when the certificate expires.
+fn load_certificate(
+    crt_path: &Path,
+    key_path: &Path,
+) -> Result<(Certificate, i64), Box<dyn Error>> {
+    let certs = load_certs(crt_path)?;
+    let key = load_private_key(key_path)?;
+
+    let leaf = certs
+        .first()
+        .ok_or(format!("no certificates in {}", crt_path.display()))?;
+    let (_, cert) = parse_x509_certificate(leaf)?;
+
+    let mut names: Vec<String> = cert
+        .subject_alternative_name()?
+        .map(|san| {
+            san.value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(name) => Some(name.to_ascii_lowercase()),
+                    _ => None,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    if names.is_empty() {
+        names = cert
+            .subject()
+            .iter_common_name()
+            .filter_map(|cn| cn.as_str().ok())
+            .map(|cn| cn.to_ascii_lowercase())
+            .collect();
+    }
+    let not_after = cert.validity().not_after.timestamp();
+
+    let key = CertifiedKey::new(certs, any_supported_type(&key)?);
+    let certificate = Certificate {
+        names,
+        key: Arc::new(key),
+    };
+    Ok((certificate, not_after))
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let cert_file = fs::File::open(path)?;
+    let mut reader = io::BufReader::new(cert_file);
+    rustls_pemfile::certs(&mut reader).collect()
+}
+
+fn load_private_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    let key_file = fs::File::open(path)?;
+    let mut reader = io::BufReader::new(key_file);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or(io::Error::new(io::ErrorKind::InvalidData, "no private key"))
+}
+
+#[instrument("tls background service", skip_all)]
+pub fn start(state: Arc<State>) {
+    if state.tls.is_none() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<Event>(1);
+
+        let watcher_config = notify::Config::default()
+            .with_compare_contents(true)
+            .with_poll_interval(state.config.ssl_poll_interval);
+
+        let watcher_result = PollWatcher::new(
+            move |res| {
+                if let Ok(event) = res {
+                    // A pending event already triggers a reload.
+                    let _ = tx.try_send(event);
+                }
+            },
+            watcher_config,
+        );
+        if let Err(err) = watcher_result {
+            error!(error = err.to_string(), "error to watch certificates");
+            return;
+        }
+
+        let mut watcher = watcher_result.unwrap();
+        let paths = match &state.config.ssl_certs_path {
+            Some(certs_path) => vec![certs_path],
+            None => [&state.config.ssl_crt_path, &state.config.ssl_key_path]
+                .into_iter()
+                .flatten()
+                .collect(),
+        };
+        for path in paths {
+            if let Err(err) = watcher.watch(path, RecursiveMode::NonRecursive) {
+                error!(error = err.to_string(), "error to watch certificates");
+                return;
+            }
+        }
+
+        while rx.recv().await.is_some() {
+            let Some(tls) = &state.tls else {
+                return;
+            };
+            if let Err(err) = tls.reload(&state.config, &state.metrics) {
+                error!(error = err.to_string(), "error to reload certificates");
+                continue;
+            }
+
+            info!("certificates reloaded");
+        }
+    });
+}
@@ -0,0 +1,795 @@
+use serde_json::{json, Map, Value};
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::jsonrpc::{message_value, Envelope, RpcRequest};
+
+// Translation between Ogmios v5 (JSON-WSP) and Ogmios v6 (JSON-RPC), so v5 clients can be served
+// by v6 instances. Only the envelopes and the shapes of the common methods are translated,
+// payloads without a direct v5 equivalent (block bodies, transactions, protocol parameters) keep
+// their v6 shape.
+
+pub const UNSUPPORTED_METHOD: &str = "Method not supported by the Ogmios v5 translation.";
+pub const UNSUPPORTED_QUERY: &str = "Query not supported by the Ogmios v5 translation.";
+pub const INVALID_ARGS: &str = "Invalid arguments for the Ogmios v5 method.";
+
+const METHODS: &[(&str, &str)] = &[
+    ("FindIntersect", "findIntersection"),
+    ("RequestNext", "nextBlock"),
+    ("SubmitTx", "submitTransaction"),
+    ("EvaluateTx", "evaluateTransaction"),
+    ("Acquire", "acquireLedgerState"),
+    ("Release", "releaseLedgerState"),
+    ("AwaitAcquire", "acquireMempool"),
+    ("NextTx", "nextTransaction"),
+    ("HasTx", "hasTransaction"),
+    ("SizeAndCapacity", "sizeOfMempool"),
+    ("ReleaseMempool", "releaseMempool"),
+];
+
+const QUERIES: &[(&str, &str)] = &[
+    ("chainTip", "queryNetwork/tip"),
+    ("blockHeight", "queryNetwork/blockHeight"),
+    ("systemStart", "queryNetwork/startTime"),
+    ("ledgerTip", "queryLedgerState/tip"),
+    ("currentEpoch", "queryLedgerState/epoch"),
+    ("eraStart", "queryLedgerState/eraStart"),
+    ("eraSummaries", "queryLedgerState/eraSummaries"),
+    (
+        "currentProtocolParameters",
+        "queryLedgerState/protocolParameters",
+    ),
+    (
+        "proposedProtocolParameters",
+        "queryLedgerState/proposedProtocolParameters",
+    ),
+    (
+        "stakeDistribution",
+        "queryLedgerState/liveStakeDistribution",
+    ),
+    ("poolIds", "queryLedgerState/stakePools"),
+    ("rewardsProvenance'", "queryLedgerState/rewardsProvenance"),
+];
+
+/// Translates a v5 request into the equivalent v6 request. The v5 `mirror` becomes the v6 `id`,
+/// so the response can be translated back without keeping any state.
+pub fn request(request: &RpcRequest) -> Result<RpcRequest, &'static str> {
+    let args = request.params.clone().unwrap_or(Value::Null);
+
+    let (method, params) = match request.method.as_str() {
+        "FindIntersect" => {
+            let points = args
+                .get("points")
+                .and_then(Value::as_array)
+                .ok_or(INVALID_ARGS)?;
+            let points: Vec<Value> = points.iter().map(point_to_v6).collect();
+            ("findIntersection", Some(json!({ "points": points })))
+        }
+        "RequestNext" => ("nextBlock", None),
+        "SubmitTx" => {
+            let cbor = args.get("submit").ok_or(INVALID_ARGS)?;
+            (
+                "submitTransaction",
+                Some(json!({ "transaction": { "cbor": cbor } })),
+            )
+        }
+        "EvaluateTx" => {
+            // The v5 additional utxo set has no direct v6 equivalent.
+            if args.get("additionalUtxoSet").is_some() {
+                return Err(INVALID_ARGS);
+            }
+            let cbor = args.get("evaluate").ok_or(INVALID_ARGS)?;
+            (
+                "evaluateTransaction",
+                Some(json!({ "transaction": { "cbor": cbor } })),
+            )
+        }
+        "Acquire" => {
+            let point = args.get("point").ok_or(INVALID_ARGS)?;
+            (
+                "acquireLedgerState",
+                Some(json!({ "point": point_to_v6(point) })),
+            )
+        }
+        "Release" => ("releaseLedgerState", None),
+        "AwaitAcquire" => ("acquireMempool", None),
+        "NextTx" => (
+            "nextTransaction",
+            args.get("fields").map(|fields| json!({ "fields": fields })),
+        ),
+        "HasTx" => {
+            let id = args.get("id").ok_or(INVALID_ARGS)?;
+            ("hasTransaction", Some(json!({ "id": id })))
+        }
+        "SizeAndCapacity" => ("sizeOfMempool", None),
+        "ReleaseMempool" => ("releaseMempool", None),
+        "Query" => query(args.get("query").ok_or(INVALID_ARGS)?)?,
+        _ => return Err(UNSUPPORTED_METHOD),
+    };
+
+    Ok(RpcRequest {
+        envelope: Envelope::JsonRpc,
+        method: method.into(),
+        params,
+        id: request.id.clone(),
+    })
+}
+
+fn query(query: &Value) -> Result<(&'static str, Option<Value>), &'static str> {
+    if let Some(name) = query.as_str() {
+        return QUERIES
+            .iter()
+            .find(|(v5, _)| *v5 == name)
+            .map(|(_, v6)| (*v6, None))
+            .ok_or(UNSUPPORTED_QUERY);
+    }
+
+    // Utxos are filtered either by addresses or by output references.
+    if let Some(filters) = query.get("utxo").and_then(Value::as_array) {
+        if filters.iter().all(Value::is_string) {
+            return Ok((
+                "queryLedgerState/utxo",
+                Some(json!({ "addresses": filters })),
+            ));
+        }
+
+        let references = filters
+            .iter()
+            .map(|filter| {
+                let id = filter.get("txId")?;
+                let index = filter.get("index")?;
+                Some(json!({ "transaction": { "id": id }, "index": index }))
+            })
+            .collect::<Option<Vec<Value>>>()
+            .ok_or(UNSUPPORTED_QUERY)?;
+        return Ok((
+            "queryLedgerState/utxo",
+            Some(json!({ "outputReferences": references })),
+        ));
+    }
+
+    Err(UNSUPPORTED_QUERY)
+}
+
+/// Translates a v6 response into the v5 response of the original request. Frames that aren't
+/// JSON-RPC, like errors built by the proxy for a v5 request, are returned as is.
+pub fn response(response: Value) -> Value {
+    if response.get("jsonrpc").is_none() {
+        return response;
+    }
+
+    let method = response
+        .get("method")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    let methodname = match method {
+        query if query.starts_with("query") => Some("Query"),
+        _ => METHODS
+            .iter()
+            .find(|(_, v6)| *v6 == method)
+            .map(|(v5, _)| *v5),
+    };
+
+    let result = match (response.get("result"), response.get("error")) {
+        (Some(result), _) => Ok(result_to_v5(method, result)),
+        (None, Some(error)) => error_to_v5(method, error),
+        (None, None) => Err(json!({ "code": "server", "string": "Invalid response." })),
+    };
+
+    let mut frame = json!({
+        "type": "jsonwsp/response",
+        "version": "1.0",
+        "servicename": "ogmios",
+        "methodname": methodname,
+        "reflection": response.get("id"),
+    });
+    match result {
+        Ok(result) => frame["result"] = result,
+        Err(fault) => {
+            frame["type"] = json!("jsonwsp/fault");
+            frame["fault"] = fault;
+        }
+    }
+    frame
+}
+
+pub fn response_message(message: Message) -> Message {
+    match message_value(&message) {
+        Some(value) if value.get("jsonrpc").is_some() => Message::Text(response(value).to_string()),
+        _ => message,
+    }
+}
+
+fn result_to_v5(method: &str, result: &Value) -> Value {
+    match method {
+        "findIntersection" => json!({
+            "IntersectionFound": {
+                "point": point_to_v5(&result["intersection"]),
+                "tip": tip_to_v5(&result["tip"]),
+            }
+        }),
+        "nextBlock" => match result["direction"].as_str() {
+            Some("backward") => json!({
+                "RollBackward": {
+                    "point": point_to_v5(&result["point"]),
+                    "tip": tip_to_v5(&result["tip"]),
+                }
+            }),
+            _ => json!({
+                "RollForward": {
+                    "block": block_to_v5(&result["block"]),
+                    "tip": tip_to_v5(&result["tip"]),
+                }
+            }),
+        },
+        "submitTransaction" => json!({
+            "SubmitSuccess": { "txId": result["transaction"]["id"] }
+        }),
+        "evaluateTransaction" => {
+            let budgets: Map<String, Value> = result
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(|evaluation| {
+                    // The validator is either "purpose:index" or an object, depending on the
+                    // Ogmios v6 release.
+                    let validator = match &evaluation["validator"] {
+                        Value::String(validator) => validator.clone(),
+                        validator => format!(
+                            "{}:{}",
+                            validator["purpose"].as_str().unwrap_or_default(),
+                            validator["index"]
+                        ),
+                    };
+                    let budget = json!({
+                        "memory": evaluation["budget"]["memory"],
+                        "steps": evaluation["budget"]["cpu"],
+                    });
+                    (validator, budget)
+                })
+                .collect();
+            json!({ "EvaluationResult": budgets })
+        }
+        "acquireLedgerState" => json!({
+            "AcquireSuccess": { "point": point_to_v5(&result["point"]) }
+        }),
+        "releaseLedgerState" | "releaseMempool" => json!("Released"),
+        "acquireMempool" => json!({ "AwaitAcquired": { "slot": result["slot"] } }),
+        "nextTransaction" => match &result["transaction"] {
+            Value::Object(transaction) if transaction.len() == 1 => transaction
+                .get("id")
+                .cloned()
+                .unwrap_or(Value::Object(transaction.clone())),
+            transaction => transaction.clone(),
+        },
+        "sizeOfMempool" => json!({
+            "capacity": result["maxCapacity"]["bytes"],
+            "currentSize": result["currentSize"]["bytes"],
+            "numberOfTxs": result["transactions"]["count"],
+        }),
+        "queryNetwork/tip" | "queryLedgerState/tip" => tip_to_v5(result),
+        "queryLedgerState/utxo" => Value::Array(
+            result
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(utxo_to_v5)
+                .collect(),
+        ),
+        _ => result.clone(),
+    }
+}
+
+/// Chain-sync and tx-submission failures are v5 results, everything else is a fault.
+fn error_to_v5(method: &str, error: &Value) -> Result<Value, Value> {
+    let code = error["code"].as_i64().unwrap_or_default();
+    let message = error["message"].as_str().unwrap_or_default();
+
+    // JSON-RPC protocol errors and errors built by the proxy.
+    if (-32768..=-32000).contains(&code) {
+        let mut fault = json!({ "code": "client", "string": message });
+        if let Some(data) = error.get("data") {
+            fault["data"] = data.clone();
+        }
+        return Err(fault);
+    }
+
+    match method {
+        "findIntersection" => Ok(json!({
+            "IntersectionNotFound": { "tip": tip_to_v5(&error["data"]["tip"]) }
+        })),
+        "submitTransaction" => Ok(json!({ "SubmitFail": [error] })),
+        "evaluateTransaction" => Ok(json!({ "EvaluationFailure": error })),
+        "acquireLedgerState" => {
+            let failure = match message.contains("too old") {
+                true => "pointTooOld",
+                false => "pointNotOnChain",
+            };
+            Ok(json!({ "AcquireFailure": { "failure": failure } }))
+        }
+        _ => Err(json!({ "code": "server", "string": message })),
+    }
+}
+
+fn point_to_v6(point: &Value) -> Value {
+    match point {
+        Value::Object(_) => json!({ "slot": point["slot"], "id": point["hash"] }),
+        point => point.clone(),
+    }
+}
+
+fn point_to_v5(point: &Value) -> Value {
+    match point {
+        Value::Object(_) => json!({ "slot": point["slot"], "hash": point["id"] }),
+        point => point.clone(),
+    }
+}
+
+fn tip_to_v5(tip: &Value) -> Value {
+    match tip {
+        Value::Object(_) => json!({
+            "slot": tip["slot"],
+            "hash": tip["id"],
+            "blockNo": tip["height"],
+        }),
+        tip => tip.clone(),
+    }
+}
+
+fn block_to_v5(block: &Value) -> Value {
+    let era = block["era"].as_str().unwrap_or("byron").to_string();
+    let content = json!({
+        "headerHash": block["id"],
+        "header": {
+            "slot": block["slot"],
+            "blockHeight": block["height"],
+            "prevHash": block["ancestor"],
+        },
+        "body": block.get("transactions").cloned().unwrap_or(json!([])),
+    });
+    Value::Object(Map::from_iter([(era, content)]))
+}
+
+fn utxo_to_v5(utxo: &Value) -> Value {
+    let mut assets = Map::new();
+    if let Some(value) = utxo["value"].as_object() {
+        for (policy, tokens) in value.iter().filter(|(policy, _)| *policy != "ada") {
+            for (name, quantity) in tokens.as_object().into_iter().flatten() {
+                let asset = match name.is_empty() {
+                    true => policy.clone(),
+                    false => format!("{policy}.{name}"),
+                };
+                assets.insert(asset, quantity.clone());
+            }
+        }
+    }
+
+    let mut output = json!({
+        "address": utxo["address"],
+        "value": {
+            "coins": utxo["value"]["ada"]["lovelace"],
+            "assets": assets,
+        },
+    });
+    for field in ["datumHash", "datum", "script"] {
+        if let Some(value) = utxo.get(field) {
+            output[field] = value.clone();
+        }
+    }
+
+    json!([
+        { "txId": utxo["transaction"]["id"], "index": utxo["index"] },
+        output,
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v5_request(methodname: &str, args: Value) -> RpcRequest {
+        let frame = json!({
+            "type": "jsonwsp/request",
+            "version": "1.0",
+            "servicename": "ogmios",
+            "methodname": methodname,
+            "args": args,
+            "mirror": { "step": 42 },
+        });
+        RpcRequest::parse(frame.to_string().as_bytes()).unwrap()
+    }
+
+    fn v6_request(methodname: &str, args: Value) -> (String, Option<Value>) {
+        let translated = request(&v5_request(methodname, args)).unwrap();
+        assert_eq!(translated.envelope, Envelope::JsonRpc);
+        assert_eq!(translated.id, Some(json!({ "step": 42 })));
+        (translated.method, translated.params)
+    }
+
+    fn v6_response(method: &str, result: Value) -> Value {
+        response(json!({
+            "jsonrpc": "2.0",
+            "method": method,
+            "result": result,
+            "id": { "step": 42 },
+        }))
+    }
+
+    fn v6_error(method: &str, code: i64, message: &str, data: Value) -> Value {
+        response(json!({
+            "jsonrpc": "2.0",
+            "method": method,
+            "error": { "code": code, "message": message, "data": data },
+            "id": { "step": 42 },
+        }))
+    }
+
+    #[test]
+    fn translates_requests() {
+        let point = json!({ "slot": 10, "hash": "abc" });
+        let cases = [
+            (
+                "FindIntersect",
+                json!({ "points": [point, "origin"] }),
+                "findIntersection",
+                Some(json!({ "points": [{ "slot": 10, "id": "abc" }, "origin"] })),
+            ),
+            ("RequestNext", json!({}), "nextBlock", None),
+            (
+                "SubmitTx",
+                json!({ "submit": "84a3" }),
+                "submitTransaction",
+                Some(json!({ "transaction": { "cbor": "84a3" } })),
+            ),
+            (
+                "EvaluateTx",
+                json!({ "evaluate": "84a3" }),
+                "evaluateTransaction",
+                Some(json!({ "transaction": { "cbor": "84a3" } })),
+            ),
+            (
+                "Acquire",
+                json!({ "point": point }),
+                "acquireLedgerState",
+                Some(json!({ "point": { "slot": 10, "id": "abc" } })),
+            ),
+            ("Release", json!({}), "releaseLedgerState", None),
+            ("AwaitAcquire", json!({}), "acquireMempool", None),
+            (
+                "NextTx",
+                json!({ "fields": "all" }),
+                "nextTransaction",
+                Some(json!({ "fields": "all" })),
+            ),
+            ("NextTx", json!({}), "nextTransaction", None),
+            (
+                "HasTx",
+                json!({ "id": "def" }),
+                "hasTransaction",
+                Some(json!({ "id": "def" })),
+            ),
+            ("SizeAndCapacity", json!({}), "sizeOfMempool", None),
+            ("ReleaseMempool", json!({}), "releaseMempool", None),
+            (
+                "Query",
+                json!({ "query": "chainTip" }),
+                "queryNetwork/tip",
+                None,
+            ),
+            (
+                "Query",
+                json!({ "query": "currentProtocolParameters" }),
+                "queryLedgerState/protocolParameters",
+                None,
+            ),
+            (
+                "Query",
+                json!({ "query": { "utxo": ["addr1"] } }),
+                "queryLedgerState/utxo",
+                Some(json!({ "addresses": ["addr1"] })),
+            ),
+            (
+                "Query",
+                json!({ "query": { "utxo": [{ "txId": "def", "index": 1 }] } }),
+                "queryLedgerState/utxo",
+                Some(
+                    json!({ "outputReferences": [{ "transaction": { "id": "def" }, "index": 1 }] }),
+                ),
+            ),
+        ];
+
+        for (methodname, args, method, params) in cases {
+            assert_eq!(
+                v6_request(methodname, args),
+                (method.to_string(), params),
+                "{methodname}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_requests_without_translation() {
+        let cases = [
+            ("Unknown", json!({}), UNSUPPORTED_METHOD),
+            ("FindIntersect", json!({}), INVALID_ARGS),
+            ("SubmitTx", json!({}), INVALID_ARGS),
+            (
+                "EvaluateTx",
+                json!({ "evaluate": "84a3", "additionalUtxoSet": [] }),
+                INVALID_ARGS,
+            ),
+            ("Acquire", json!({}), INVALID_ARGS),
+            ("HasTx", json!({}), INVALID_ARGS),
+            ("Query", json!({}), INVALID_ARGS),
+            (
+                "Query",
+                json!({ "query": "unknownQuery" }),
+                UNSUPPORTED_QUERY,
+            ),
+            (
+                "Query",
+                json!({ "query": { "utxo": [{ "txId": "def" }] } }),
+                UNSUPPORTED_QUERY,
+            ),
+            (
+                "Query",
+                json!({ "query": { "other": [] } }),
+                UNSUPPORTED_QUERY,
+            ),
+        ];
+
+        for (methodname, args, reason) in cases {
+            assert_eq!(
+                request(&v5_request(methodname, args)).unwrap_err(),
+                reason,
+                "{methodname}"
+            );
+        }
+    }
+
+    #[test]
+    fn round_trips_requests_and_responses() {
+        let translated = request(&v5_request("RequestNext", json!({}))).unwrap();
+        let forwarded = RpcRequest::parse(translated.value().to_string().as_bytes()).unwrap();
+        assert_eq!(forwarded.method, "nextBlock");
+
+        let tip = json!({ "slot": 20, "id": "fff", "height": 5 });
+        let v5 = response(json!({
+            "jsonrpc": "2.0",
+            "method": forwarded.method,
+            "result": { "direction": "backward", "point": "origin", "tip": tip },
+            "id": forwarded.id,
+        }));
+        assert_eq!(
+            v5,
+            json!({
+                "type": "jsonwsp/response",
+                "version": "1.0",
+                "servicename": "ogmios",
+                "methodname": "RequestNext",
+                "result": {
+                    "RollBackward": {
+                        "point": "origin",
+                        "tip": { "slot": 20, "hash": "fff", "blockNo": 5 },
+                    }
+                },
+                "reflection": { "step": 42 },
+            })
+        );
+    }
+
+    #[test]
+    fn translates_responses() {
+        let tip = json!({ "slot": 20, "id": "fff", "height": 5 });
+        let v5_tip = json!({ "slot": 20, "hash": "fff", "blockNo": 5 });
+        let cases = [
+            (
+                "findIntersection",
+                json!({ "intersection": { "slot": 10, "id": "abc" }, "tip": tip }),
+                "FindIntersect",
+                json!({ "IntersectionFound": { "point": { "slot": 10, "hash": "abc" }, "tip": v5_tip } }),
+            ),
+            (
+                "nextBlock",
+                json!({
+                    "direction": "forward",
+                    "block": { "era": "babbage", "id": "abc", "slot": 10, "height": 4, "ancestor": "aaa", "transactions": [] },
+                    "tip": tip,
+                }),
+                "RequestNext",
+                json!({
+                    "RollForward": {
+                        "block": {
+                            "babbage": {
+                                "headerHash": "abc",
+                                "header": { "slot": 10, "blockHeight": 4, "prevHash": "aaa" },
+                                "body": [],
+                            }
+                        },
+                        "tip": v5_tip,
+                    }
+                }),
+            ),
+            (
+                "submitTransaction",
+                json!({ "transaction": { "id": "def" } }),
+                "SubmitTx",
+                json!({ "SubmitSuccess": { "txId": "def" } }),
+            ),
+            (
+                "evaluateTransaction",
+                json!([
+                    { "validator": "spend:0", "budget": { "memory": 1, "cpu": 2 } },
+                    { "validator": { "purpose": "mint", "index": 1 }, "budget": { "memory": 3, "cpu": 4 } },
+                ]),
+                "EvaluateTx",
+                json!({
+                    "EvaluationResult": {
+                        "spend:0": { "memory": 1, "steps": 2 },
+                        "mint:1": { "memory": 3, "steps": 4 },
+                    }
+                }),
+            ),
+            (
+                "acquireLedgerState",
+                json!({ "acquired": "ledgerState", "point": { "slot": 10, "id": "abc" } }),
+                "Acquire",
+                json!({ "AcquireSuccess": { "point": { "slot": 10, "hash": "abc" } } }),
+            ),
+            (
+                "releaseLedgerState",
+                json!({ "released": "ledgerState" }),
+                "Release",
+                json!("Released"),
+            ),
+            (
+                "acquireMempool",
+                json!({ "acquired": "mempool", "slot": 10 }),
+                "AwaitAcquire",
+                json!({ "AwaitAcquired": { "slot": 10 } }),
+            ),
+            (
+                "nextTransaction",
+                json!({ "transaction": { "id": "def" } }),
+                "NextTx",
+                json!("def"),
+            ),
+            (
+                "nextTransaction",
+                json!({ "transaction": null }),
+                "NextTx",
+                json!(null),
+            ),
+            ("hasTransaction", json!(true), "HasTx", json!(true)),
+            (
+                "sizeOfMempool",
+                json!({
+                    "maxCapacity": { "bytes": 100 },
+                    "currentSize": { "bytes": 10 },
+                    "transactions": { "count": 1 },
+                }),
+                "SizeAndCapacity",
+                json!({ "capacity": 100, "currentSize": 10, "numberOfTxs": 1 }),
+            ),
+            (
+                "releaseMempool",
+                json!({ "released": "mempool" }),
+                "ReleaseMempool",
+                json!("Released"),
+            ),
+            ("queryNetwork/tip", tip.clone(), "Query", v5_tip.clone()),
+            ("queryLedgerState/epoch", json!(400), "Query", json!(400)),
+            (
+                "queryLedgerState/utxo",
+                json!([{
+                    "transaction": { "id": "def" },
+                    "index": 1,
+                    "address": "addr1",
+                    "value": { "ada": { "lovelace": 5 }, "abcd": { "": 1, "ef": 2 } },
+                    "datumHash": "123",
+                }]),
+                "Query",
+                json!([[
+                    { "txId": "def", "index": 1 },
+                    {
+                        "address": "addr1",
+                        "value": { "coins": 5, "assets": { "abcd": 1, "abcd.ef": 2 } },
+                        "datumHash": "123",
+                    },
+                ]]),
+            ),
+        ];
+
+        for (method, result, methodname, v5_result) in cases {
+            let v5 = v6_response(method, result);
+            assert_eq!(v5["type"], "jsonwsp/response", "{method}");
+            assert_eq!(v5["methodname"], methodname, "{method}");
+            assert_eq!(v5["reflection"], json!({ "step": 42 }), "{method}");
+            assert_eq!(v5["result"], v5_result, "{method}");
+        }
+    }
+
+    #[test]
+    fn translates_failures_to_results() {
+        let tip = json!({ "slot": 20, "id": "fff", "height": 5 });
+        let v5 = v6_error(
+            "findIntersection",
+            1000,
+            "no intersection",
+            json!({ "tip": tip }),
+        );
+        assert_eq!(v5["type"], "jsonwsp/response");
+        assert_eq!(
+            v5["result"],
+            json!({ "IntersectionNotFound": { "tip": { "slot": 20, "hash": "fff", "blockNo": 5 } } })
+        );
+
+        let v5 = v6_error("submitTransaction", 3005, "era mismatch", json!({}));
+        assert_eq!(v5["methodname"], "SubmitTx");
+        assert_eq!(
+            v5["result"]["SubmitFail"][0]["message"],
+            json!("era mismatch")
+        );
+
+        let v5 = v6_error("evaluateTransaction", 3010, "script failure", json!([]));
+        assert_eq!(v5["result"]["EvaluationFailure"]["code"], json!(3010));
+
+        let v5 = v6_error("acquireLedgerState", 2000, "point too old", json!(null));
+        assert_eq!(
+            v5["result"],
+            json!({ "AcquireFailure": { "failure": "pointTooOld" } })
+        );
+        let v5 = v6_error(
+            "acquireLedgerState",
+            2000,
+            "point not on chain",
+            json!(null),
+        );
+        assert_eq!(
+            v5["result"],
+            json!({ "AcquireFailure": { "failure": "pointNotOnChain" } })
+        );
+    }
+
+    #[test]
+    fn translates_faults() {
+        // Errors built by the proxy keep their data.
+        let v5 = v6_error(
+            "nextBlock",
+            -32003,
+            "rate limited",
+            json!({ "retryAfter": 1 }),
+        );
+        assert_eq!(v5["type"], "jsonwsp/fault");
+        assert_eq!(v5["methodname"], "RequestNext");
+        assert_eq!(v5["reflection"], json!({ "step": 42 }));
+        assert_eq!(
+            v5["fault"],
+            json!({ "code": "client", "string": "rate limited", "data": { "retryAfter": 1 } })
+        );
+
+        let v5 = v6_error("queryLedgerState/utxo", 2001, "era mismatch", json!(null));
+        assert_eq!(v5["type"], "jsonwsp/fault");
+        assert_eq!(v5["methodname"], "Query");
+        assert_eq!(
+            v5["fault"],
+            json!({ "code": "server", "string": "era mismatch" })
+        );
+
+        let v5 = response(json!({ "jsonrpc": "2.0", "method": "nextBlock", "id": null }));
+        assert_eq!(v5["type"], "jsonwsp/fault");
+        assert_eq!(v5["fault"]["code"], "server");
+    }
+
+    #[test]
+    fn keeps_v5_frames() {
+        let fault = v5_request("Unknown", json!({})).error(-32002, UNSUPPORTED_METHOD, None);
+        assert_eq!(response(fault.clone()), fault);
+
+        let message = Message::Text(fault.to_string());
+        assert_eq!(response_message(message.clone()), message);
+        assert_eq!(
+            response_message(Message::Ping(Vec::new())),
+            Message::Ping(Vec::new())
+        );
+    }
+}
@@ -0,0 +1,196 @@
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::{TokioExecutor, TokioTimer};
+use prometheus::IntGauge;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::config::Config;
+use crate::health::InstanceHealth;
+use crate::metrics::Metrics;
+use crate::State;
+
+pub type HttpClient = Client<HttpConnector, Full<Bytes>>;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Strategy {
+    RoundRobin,
+    LeastConnections,
+}
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "least-connections" => Ok(Strategy::LeastConnections),
+            _ => Err(format!("invalid upstream strategy {s}")),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Upstream {
+    pub addr: String,
+    active_connections: AtomicUsize,
+    gauge: IntGauge,
+}
+
+/// Keeps the upstream selected while alive, so long lived websocket sessions count as active
+/// connections of the upstream they are pinned to.
+#[derive(Debug)]
+pub struct UpstreamLease {
+    upstream: Arc<Upstream>,
+}
+impl UpstreamLease {
+    fn new(upstream: Arc<Upstream>) -> Self {
+        upstream.active_connections.fetch_add(1, Ordering::SeqCst);
+        upstream.gauge.inc();
+        Self { upstream }
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.upstream.addr
+    }
+}
+impl Drop for UpstreamLease {
+    fn drop(&mut self) {
+        self.upstream
+            .active_connections
+            .fetch_sub(1, Ordering::SeqCst);
+        self.upstream.gauge.dec();
+    }
+}
+
+#[derive(Debug)]
+pub struct UpstreamPool {
+    upstreams: Vec<Arc<Upstream>>,
+    next: AtomicUsize,
+}
+impl UpstreamPool {
+    fn new(metrics: &Metrics, addrs: &[String]) -> Self {
+        let upstreams = addrs
+            .iter()
+            .map(|addr| {
+                Arc::new(Upstream {
+                    addr: addr.clone(),
+                    active_connections: AtomicUsize::new(0),
+                    gauge: metrics
+                        .upstream_active_connections
+                        .with_label_values(&[addr]),
+                })
+            })
+            .collect();
+
+        Self {
+            upstreams,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn addrs(&self) -> Vec<String> {
+        self.upstreams.iter().map(|u| u.addr.clone()).collect()
+    }
+
+    /// Selects an upstream between the healthy ones. When none of them is healthy, the health
+    /// of the first unhealthy upstream is returned to explain the refusal.
+    fn select(
+        &self,
+        strategy: Strategy,
+        health: &HashMap<String, InstanceHealth>,
+        min_network_synchronization: f64,
+    ) -> Result<UpstreamLease, InstanceHealth> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let total = self.upstreams.len();
+
+        // Upstreams not polled yet are considered healthy.
+        let mut candidates = (0..total)
+            .map(|i| &self.upstreams[(start + i) % total])
+            .filter(|upstream| {
+                health
+                    .get(&upstream.addr)
+                    .map(|h| h.is_healthy(min_network_synchronization))
+                    .unwrap_or(true)
+            });
+
+        let selected = match strategy {
+            Strategy::RoundRobin => candidates.next(),
+            Strategy::LeastConnections => {
+                candidates.min_by_key(|upstream| upstream.active_connections.load(Ordering::SeqCst))
+            }
+        };
+
+        match selected {
+            Some(upstream) => Ok(UpstreamLease::new(upstream.clone())),
+            None => Err(self
+                .upstreams
+                .iter()
+                .find_map(|upstream| health.get(&upstream.addr).cloned())
+                .unwrap_or_default()),
+        }
+    }
+}
+
+fn pool_key(network: &str, version: &str) -> String {
+    format!("{network}-{version}")
+}
+
+pub fn build_pools(config: &Config, metrics: &Metrics) -> HashMap<String, Arc<UpstreamPool>> {
+    config
+        .ogmios_upstreams
+        .iter()
+        .map(|(key, addrs)| (key.clone(), Arc::new(UpstreamPool::new(metrics, addrs))))
+        .collect()
+}
+
+/// Client used to forward http requests, it keeps idle connections to each upstream alive to
+/// be reused by the next requests.
+pub fn build_http_client(config: &Config) -> HttpClient {
+    let mut connector = HttpConnector::new();
+    connector.set_connect_timeout(Some(config.ogmios_http_connect_timeout));
+    connector.set_nodelay(true);
+
+    Client::builder(TokioExecutor::new())
+        .pool_idle_timeout(config.ogmios_http_pool_idle_timeout)
+        .pool_max_idle_per_host(config.ogmios_http_pool_max_idle)
+        .pool_timer(TokioTimer::new())
+        .http1_preserve_header_case(true)
+        .http1_title_case_headers(true)
+        .build(connector)
+}
+
+/// Pool of upstreams of the network and version. When no pool is configured, the pool has
+/// only the ogmios instance service.
+pub async fn get_pool(state: &State, network: &str, version: &str) -> Arc<UpstreamPool> {
+    let key = pool_key(network, version);
+    if let Some(pool) = state.upstreams.read().await.get(&key) {
+        return pool.clone();
+    }
+
+    let addrs = vec![state.instance_addr(network, version)];
+    state
+        .upstreams
+        .write()
+        .await
+        .entry(key)
+        .or_insert_with(|| Arc::new(UpstreamPool::new(&state.metrics, &addrs)))
+        .clone()
+}
+
+pub async fn select(
+    state: &State,
+    network: &str,
+    version: &str,
+) -> Result<UpstreamLease, InstanceHealth> {
+    let pool = get_pool(state, network, version).await;
+    let health = state.instances_health.read().await;
+    pool.select(
+        state.config.ogmios_upstream_strategy,
+        &health,
+        state.config.ogmios_min_network_synchronization,
+    )
+}
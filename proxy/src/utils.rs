@@ -3,6 +3,12 @@ use http_body_util::{combinators::BoxBody, BodyExt, Full};
 use hyper::{body::Incoming, Request, Response};
 
 pub const DMTR_API_KEY: &str = "dmtr-api-key";
+pub const X_RATE_LIMIT_LIMIT: &str = "x-ratelimit-limit";
+pub const X_RATE_LIMIT_REMAINING: &str = "x-ratelimit-remaining";
+pub const X_RATE_LIMIT_RESET: &str = "x-ratelimit-reset";
+pub const X_FORWARDED_FOR: &str = "x-forwarded-for";
+pub const X_FORWARDED_HOST: &str = "x-forwarded-host";
+pub const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
 
 pub type Body = BoxBody<Bytes, hyper::Error>;
 pub type ProxyResponse = Response<Body>;
@@ -0,0 +1,715 @@
+use futures_channel::mpsc;
+use futures_util::{SinkExt, StreamExt};
+use hyper::upgrade::Upgraded;
+use hyper::Uri;
+use hyper_util::rt::TokioIo;
+use serde_json::{json, Value};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, WebSocketStream};
+use tracing::{debug, error, info};
+use url::Url;
+
+use crate::bandwidth::{request_bandwidth, response_bandwidth};
+use crate::cache::{
+    CacheKey, ChainPosition, Flight, FlightGuard, CACHE_COALESCED, CACHE_HIT, CACHE_MISS,
+};
+use crate::jsonrpc::{
+    answers, id_key, invalid_request, message_value, Envelope, RpcRequest, RpcResponse,
+    BYTE_QUOTA_EXCEEDED, BYTE_QUOTA_EXCEEDED_MESSAGE, METHOD_NOT_ALLOWED,
+    METHOD_NOT_ALLOWED_MESSAGE, METHOD_NOT_SUPPORTED, QUOTA_EXCEEDED, QUOTA_EXCEEDED_MESSAGE,
+    RATE_LIMITED, RATE_LIMITED_MESSAGE, UNKNOWN_METHOD,
+};
+use crate::limiter::{limiter, request_quota, LimiterError};
+use crate::proxy::ProxyRequest;
+use crate::shutdown;
+use crate::tiers::is_method_allowed;
+use crate::translate;
+use crate::upstream::UpstreamLease;
+use crate::State;
+
+const CHANNEL_BUFFER: usize = 32;
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+const COALESCE_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub const CLOSE_REASON_PORT_DELETED: &str = "port deleted";
+pub const CLOSE_REASON_TIER_INVALID: &str = "tier invalid";
+pub const CLOSE_REASON_UPSTREAM_UNAVAILABLE: &str = "upstream unavailable";
+pub const CLOSE_REASON_RATE_LIMITED: &str = "rate limit exceeded";
+pub const CLOSE_REASON_BYTE_QUOTA_EXCEEDED: &str = "byte quota exceeded";
+pub const CLOSE_REASON_QUOTA_EXCEEDED: &str = "quota exceeded";
+pub const CLOSE_REASON_IDLE_TIMEOUT: &str = "idle timeout";
+pub const CLOSE_REASON_SESSION_EXPIRED: &str = "session duration exceeded";
+pub const CLOSE_REASON_GOING_AWAY: &str = "proxy shutting down";
+
+pub type ClientStream = WebSocketStream<TokioIo<Upgraded>>;
+
+struct Pending {
+    key: CacheKey,
+    method: String,
+    guard: FlightGuard,
+    /// Chain position when the request was forwarded.
+    position: ChainPosition,
+}
+
+/// Why a session ended. It decides the close frame sent to each side of the tunnel.
+#[derive(Debug)]
+enum SessionEnd {
+    /// The client sent a close frame or dropped the connection.
+    Client(Option<CloseFrame<'static>>),
+    /// The instance sent a close frame or dropped the connection.
+    Upstream(Option<CloseFrame<'static>>),
+    /// The proxy ended the session.
+    Proxy(CloseCode, &'static str),
+}
+impl SessionEnd {
+    fn client_frame(&self) -> Option<CloseFrame<'static>> {
+        match self {
+            SessionEnd::Client(_) => None,
+            // Upstream close codes are forwarded, so SDKs can decide whether to reconnect.
+            // Reserved codes can't be sent on the wire, so they are replaced.
+            SessionEnd::Upstream(Some(frame)) if frame.code.is_allowed() => Some(frame.clone()),
+            SessionEnd::Upstream(_) => Some(close_frame(
+                CloseCode::Again,
+                CLOSE_REASON_UPSTREAM_UNAVAILABLE,
+            )),
+            SessionEnd::Proxy(code, reason) => Some(close_frame(*code, reason)),
+        }
+    }
+
+    fn upstream_frame(&self) -> Option<CloseFrame<'static>> {
+        match self {
+            SessionEnd::Client(Some(frame)) if frame.code.is_allowed() => Some(frame.clone()),
+            SessionEnd::Client(_) => Some(close_frame(CloseCode::Normal, "")),
+            SessionEnd::Upstream(_) => None,
+            SessionEnd::Proxy(_, reason) => Some(close_frame(CloseCode::Normal, reason)),
+        }
+    }
+}
+impl From<LimiterError> for SessionEnd {
+    fn from(value: LimiterError) -> Self {
+        match value {
+            LimiterError::PortDeleted => {
+                SessionEnd::Proxy(CloseCode::Policy, CLOSE_REASON_PORT_DELETED)
+            }
+            LimiterError::InvalidTier => {
+                SessionEnd::Proxy(CloseCode::Error, CLOSE_REASON_TIER_INVALID)
+            }
+            LimiterError::RateLimited { .. } => {
+                SessionEnd::Proxy(CloseCode::Policy, CLOSE_REASON_RATE_LIMITED)
+            }
+            LimiterError::ByteQuotaExceeded { .. } => {
+                SessionEnd::Proxy(CloseCode::Policy, CLOSE_REASON_BYTE_QUOTA_EXCEEDED)
+            }
+            LimiterError::QuotaExceeded { .. } => {
+                SessionEnd::Proxy(CloseCode::Policy, CLOSE_REASON_QUOTA_EXCEEDED)
+            }
+        }
+    }
+}
+
+/// JSON-RPC error answering a request rejected by the limits of the tier. Other limiter errors
+/// end the session.
+fn rejection(err: &LimiterError) -> Option<(i64, &'static str, Value)> {
+    match err {
+        LimiterError::RateLimited { retry_after, .. } => Some((
+            RATE_LIMITED,
+            RATE_LIMITED_MESSAGE,
+            json!({ "retryAfter": retry_after.as_secs().max(1) }),
+        )),
+        LimiterError::ByteQuotaExceeded { retry_after } => Some((
+            BYTE_QUOTA_EXCEEDED,
+            BYTE_QUOTA_EXCEEDED_MESSAGE,
+            json!({ "retryAfter": retry_after.as_secs().max(1) }),
+        )),
+        LimiterError::QuotaExceeded {
+            period,
+            retry_after,
+        } => Some((
+            QUOTA_EXCEEDED,
+            QUOTA_EXCEEDED_MESSAGE,
+            json!({ "period": period.to_string(), "retryAfter": retry_after.as_secs().max(1) }),
+        )),
+        LimiterError::PortDeleted | LimiterError::InvalidTier => None,
+    }
+}
+
+pub fn close_frame(code: CloseCode, reason: &str) -> CloseFrame<'static> {
+    CloseFrame {
+        code,
+        reason: Cow::Owned(reason.to_string()),
+    }
+}
+
+/// Frames read from a side of the tunnel, times in milliseconds since the session started.
+#[derive(Debug, Default)]
+struct Liveness {
+    /// Time of the last frame of the side, pongs included.
+    seen: AtomicU64,
+    /// Time since the reader waits for frames of the side, or `BUSY` while it handles a frame,
+    /// e.g. waiting for the rates of the tier or for the other side to take frames.
+    waiting: AtomicU64,
+}
+impl Liveness {
+    const BUSY: u64 = u64::MAX;
+
+    fn wait(&self, now: u64) {
+        self.waiting.store(now, Ordering::Relaxed);
+    }
+
+    fn read(&self, now: u64) {
+        self.seen.store(now, Ordering::Relaxed);
+        self.waiting.store(Self::BUSY, Ordering::Relaxed);
+    }
+}
+
+/// Pings a side of the tunnel every `interval` and returns once it didn't send any frame within
+/// `timeout` of a ping. The timeout only runs while the reader of the side waits for frames, a
+/// pong can't be noticed while the reader is busy.
+async fn keepalive(
+    mut tx: mpsc::Sender<Message>,
+    liveness: &Liveness,
+    now: impl Fn() -> u64,
+    interval: Option<Duration>,
+    timeout: Duration,
+) {
+    let Some(interval) = interval else {
+        return std::future::pending().await;
+    };
+    let timeout = timeout.as_millis() as u64;
+
+    loop {
+        tokio::time::sleep(interval).await;
+
+        // A full channel already has frames waiting for the side, the ping is skipped and so is
+        // its timeout.
+        if tx.try_send(Message::Ping(Vec::new())).is_err() {
+            continue;
+        }
+        let sent = now();
+
+        let mut deadline = sent + timeout;
+        loop {
+            tokio::time::sleep(Duration::from_millis(deadline.saturating_sub(now()))).await;
+            if liveness.seen.load(Ordering::Relaxed) >= sent {
+                break;
+            }
+
+            deadline = match liveness.waiting.load(Ordering::Relaxed) {
+                Liveness::BUSY => now() + timeout,
+                waiting => waiting.max(sent) + timeout,
+            };
+            if now() >= deadline {
+                return;
+            }
+        }
+    }
+}
+
+pub async fn session(
+    mut client_stream: ClientStream,
+    proxy_req: ProxyRequest,
+    upstream: UpstreamLease,
+    uri: Uri,
+    state: Arc<State>,
+) {
+    // HTTP/2 requests have an absolute uri, only the path is forwarded.
+    let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let url = Url::parse(&format!("ws://{}{}", upstream.addr(), path)).unwrap();
+    // The `Host` header is the upstream address, the client host may carry the api key prefix.
+    let mut request = url.into_client_request().unwrap();
+    proxy_req.forwarded.insert_headers(request.headers_mut());
+    let instance_stream = match connect_async(request).await {
+        Ok((instance_stream, _)) => instance_stream,
+        Err(err) => {
+            error!(error = err.to_string(), "fail to connect to the instance");
+            let frame = close_frame(CloseCode::Again, CLOSE_REASON_UPSTREAM_UNAVAILABLE);
+            if let Err(err) = client_stream.close(Some(frame)).await {
+                error!(error = err.to_string(), "fail to close client connection");
+            }
+            return;
+        }
+    };
+
+    let (client_outgoing, mut client_incoming) = client_stream.split();
+    let (instance_outgoing, mut instance_incoming) = instance_stream.split();
+
+    state.metrics.inc_ws_total_connection(&proxy_req);
+    proxy_req.consumer.inc_connections(state.clone()).await;
+
+    let active_connections = proxy_req
+        .consumer
+        .get_active_connections(state.clone())
+        .await;
+    info!(
+        consumer = proxy_req.consumer.to_string(),
+        active_connections, "client connected"
+    );
+
+    // Frames sent to each side come from the other side and from the proxy itself (e.g.
+    // json-rpc errors, coalesced responses and close frames), so they are funneled through
+    // channels into the sinks.
+    let (mut client_tx, client_rx) = mpsc::channel::<Message>(CHANNEL_BUFFER);
+    let mut proxy_tx = client_tx.clone();
+    let translate = proxy_req.translate();
+    let (out_state, consumer) = (state.clone(), Arc::new(proxy_req.consumer.clone()));
+    let client_out = client_rx
+        .map(move |message| match translate {
+            true => translate::response_message(message),
+            false => message,
+        })
+        // Waiting for the bandwidth of the tier fills the channel, which stops reading from the
+        // instance until the client is allowed to receive more.
+        .then(move |message| {
+            let (state, consumer) = (out_state.clone(), consumer.clone());
+            async move {
+                if message.is_text() || message.is_binary() {
+                    response_bandwidth(&state, &consumer, message.len()).await;
+                }
+                message
+            }
+        })
+        .map(Ok)
+        .forward(client_outgoing);
+    tokio::pin!(client_out);
+
+    let (mut instance_tx, instance_rx) = mpsc::channel::<Message>(CHANNEL_BUFFER);
+    let instance_out = instance_rx.map(Ok).forward(instance_outgoing);
+    tokio::pin!(instance_out);
+
+    // Cacheable requests forwarded to the instance, by request id, waiting for the response.
+    let pending: Mutex<HashMap<String, Pending>> = Default::default();
+
+    // Time in milliseconds since the session started of the last message in either direction,
+    // and the frames read from each side.
+    let started = Instant::now();
+    let last_frame = AtomicU64::new(0);
+    let client_liveness = Liveness::default();
+    let instance_liveness = Liveness::default();
+    let now = || started.elapsed().as_millis() as u64;
+    let (client_ping_tx, instance_ping_tx) = (proxy_tx.clone(), instance_tx.clone());
+
+    let client_in = async {
+        let mut acquired = false;
+        // Requests waiting for an identical request of another client. They are aborted when
+        // the session ends.
+        let mut followers = JoinSet::new();
+
+        loop {
+            client_liveness.wait(now());
+            let Some(result) = client_incoming.next().await else {
+                break;
+            };
+            client_liveness.read(now());
+            while followers.try_join_next().is_some() {}
+
+            match result {
+                Ok(Message::Close(frame)) => return SessionEnd::Client(frame),
+                // Pongs answer the keepalive pings of the proxy, the instance pings are already
+                // answered by the proxy.
+                Ok(Message::Pong(_)) => continue,
+                Ok(data) => {
+                    if data.is_text() || data.is_binary() {
+                        last_frame.store(now(), Ordering::Relaxed);
+                    }
+                    let request = RpcRequest::from_message(&data);
+
+                    // v5 requests are translated, so the rest of the session only deals with v6
+                    // frames. Responses are translated back when sent to the client.
+                    let (data, request) = match request {
+                        Some(request) if translate && request.envelope == Envelope::JsonWsp => {
+                            match translate::request(&request) {
+                                Ok(translated) => (translated.message(), Some(translated)),
+                                Err(reason) => {
+                                    let message =
+                                        request.error_message(METHOD_NOT_SUPPORTED, reason, None);
+                                    if let Err(err) = proxy_tx.send(message).await {
+                                        error!(
+                                            error = err.to_string(),
+                                            "fail to send data to client"
+                                        );
+                                        return SessionEnd::Client(None);
+                                    }
+                                    continue;
+                                }
+                            }
+                        }
+                        request => (data, request),
+                    };
+                    if data.is_text() || data.is_binary() {
+                        let method = request
+                            .as_ref()
+                            .map(|r| r.method.as_str())
+                            .unwrap_or(UNKNOWN_METHOD);
+                        state.metrics.count_ws_total_request(&proxy_req, method);
+                    }
+
+                    if let Some(request) = &request {
+                        debug!(
+                            consumer = proxy_req.consumer.to_string(),
+                            method = request.method,
+                            envelope = ?request.envelope,
+                            id = ?request.id,
+                            "client request"
+                        );
+                    }
+
+                    let method = request.as_ref().map(|r| r.method.as_str());
+                    if (data.is_text() || data.is_binary())
+                        && !is_method_allowed(&state, &proxy_req.consumer.tier, method).await
+                    {
+                        info!(
+                            consumer = proxy_req.consumer.to_string(),
+                            method, "method not allowed"
+                        );
+                        let message = match &request {
+                            Some(request) => request.error_message(
+                                METHOD_NOT_ALLOWED,
+                                METHOD_NOT_ALLOWED_MESSAGE,
+                                None,
+                            ),
+                            None => Message::Text(invalid_request().to_string()),
+                        };
+                        if let Err(err) = proxy_tx.send(message).await {
+                            error!(error = err.to_string(), "fail to send data to client");
+                            return SessionEnd::Client(None);
+                        }
+                        continue;
+                    }
+
+                    // Only the requests forwarded are charged, not the control frames. The quota
+                    // is charged last, so a request rejected by another limit doesn't count.
+                    let mut result = Ok(());
+                    if data.is_text() || data.is_binary() {
+                        result = limiter(state.clone(), &proxy_req.consumer, method).await;
+                        if result.is_ok() {
+                            result =
+                                request_bandwidth(&state, &proxy_req.consumer, data.len()).await;
+                        }
+                        if result.is_ok() {
+                            result = request_quota(&state, &proxy_req.consumer).await;
+                        }
+                    }
+                    if let Err(err) = result {
+                        let Some((code, message, data)) = rejection(&err) else {
+                            error!(error = err.to_string(), "Failed to run limiter.");
+                            return SessionEnd::from(err);
+                        };
+
+                        // Rejected messages are answered by the proxy and never reach the
+                        // instance.
+                        info!(
+                            consumer = proxy_req.consumer.to_string(),
+                            method,
+                            reason = err.to_string(),
+                            "request rejected"
+                        );
+                        if let Some(request) = &request {
+                            let message = request.error_message(code, message, Some(data));
+                            if let Err(err) = proxy_tx.send(message).await {
+                                error!(error = err.to_string(), "fail to send data to client");
+                                return SessionEnd::Client(None);
+                            }
+                        }
+                        continue;
+                    }
+
+                    // Queries after acquiring a ledger state are answered at the acquired point,
+                    // so they can't be served from the cache.
+                    match method {
+                        Some("acquireLedgerState") => acquired = true,
+                        Some("releaseLedgerState") => acquired = false,
+                        _ => {}
+                    }
+
+                    if let Some(request) = request.as_ref().filter(|_| !acquired) {
+                        if let Some(key) = state.cache.key(
+                            &proxy_req.consumer.network,
+                            &proxy_req.version,
+                            request,
+                        ) {
+                            if let Some(response) = state.cache.get(&key, request).await {
+                                state.metrics.count_cache_request(
+                                    &proxy_req,
+                                    &request.method,
+                                    CACHE_HIT,
+                                );
+                                if let Err(err) =
+                                    proxy_tx.send(Message::Text(response.to_string())).await
+                                {
+                                    error!(error = err.to_string(), "fail to send data to client");
+                                    return SessionEnd::Client(None);
+                                }
+                                continue;
+                            }
+
+                            match state.cache.join(&key) {
+                                Flight::Leader(guard) => {
+                                    state.metrics.count_cache_request(
+                                        &proxy_req,
+                                        &request.method,
+                                        CACHE_MISS,
+                                    );
+                                    // Responses are correlated by id, so requests without
+                                    // an id or with the id of another pending request are
+                                    // forwarded uncached, dropping the guard.
+                                    let position = state.cache.position(&key).await;
+                                    let id = id_key(&request.id);
+                                    let mut pending = pending.lock().unwrap();
+                                    if request.id.is_some() && !pending.contains_key(&id) {
+                                        pending.insert(
+                                            id,
+                                            Pending {
+                                                key,
+                                                method: request.method.clone(),
+                                                guard,
+                                                position,
+                                            },
+                                        );
+                                    }
+                                }
+                                Flight::Follower(receiver) => {
+                                    state.metrics.count_cache_request(
+                                        &proxy_req,
+                                        &request.method,
+                                        CACHE_COALESCED,
+                                    );
+                                    let request = request.clone();
+                                    let mut client_tx = proxy_tx.clone();
+                                    let mut instance_tx = instance_tx.clone();
+                                    followers.spawn(async move {
+                                        let message =
+                                            match tokio::time::timeout(COALESCE_TIMEOUT, receiver)
+                                                .await
+                                            {
+                                                Ok(Ok(response))
+                                                    if answers(&request, &response) =>
+                                                {
+                                                    Message::Text(
+                                                        request.with_id(response).to_string(),
+                                                    )
+                                                }
+                                                // The leader failed or is too slow, so the request
+                                                // is sent to the instance.
+                                                _ => {
+                                                    let _ = instance_tx.send(data).await;
+                                                    return;
+                                                }
+                                            };
+                                        let _ = client_tx.send(message).await;
+                                    });
+                                    continue;
+                                }
+                            }
+                        }
+                    }
+
+                    if let Err(err) = instance_tx.send(data).await {
+                        error!(error = err.to_string(), "fail to send data to instance");
+                        return SessionEnd::Upstream(None);
+                    }
+                }
+                Err(err) => {
+                    error!(error = err.to_string(), "stream client incoming");
+                    return SessionEnd::Client(None);
+                }
+            }
+        }
+        SessionEnd::Client(None)
+    };
+
+    let instance_in = async {
+        loop {
+            instance_liveness.wait(now());
+            let Some(result) = instance_incoming.next().await else {
+                break;
+            };
+            instance_liveness.read(now());
+            match result {
+                Ok(Message::Close(frame)) => return SessionEnd::Upstream(frame),
+                Ok(Message::Pong(_)) => continue,
+                Ok(message) => {
+                    if message.is_text() || message.is_binary() {
+                        last_frame.store(now(), Ordering::Relaxed);
+                    }
+                    state.metrics.count_ws_total_frame(&proxy_req);
+                    if let Some(response) = RpcResponse::from_message(&message) {
+                        state.metrics.count_ws_total_response(
+                            &proxy_req,
+                            response.method(),
+                            response.status(),
+                        );
+
+                        // The id alone doesn't prove the response answers the pending request,
+                        // a client may reuse it for another method.
+                        let pending = {
+                            let mut pending = pending.lock().unwrap();
+                            let id = id_key(&response.id);
+                            match pending.get(&id) {
+                                Some(p) if response.method.as_deref() == Some(&p.method) => {
+                                    pending.remove(&id)
+                                }
+                                _ => None,
+                            }
+                        };
+                        if let Some(pending) = pending {
+                            if let Some(value) = message_value(&message) {
+                                pending.guard.complete(&value);
+                                state
+                                    .cache
+                                    .insert(pending.key, &pending.method, value, pending.position)
+                                    .await;
+                            }
+                        }
+                    }
+
+                    if let Err(err) = client_tx.send(message).await {
+                        error!(error = err.to_string(), "fail to send data to client");
+                        return SessionEnd::Client(None);
+                    }
+                }
+                Err(err) => {
+                    error!(error = err.to_string(), "stream instance incoming");
+                    return SessionEnd::Upstream(None);
+                }
+            }
+        }
+        SessionEnd::Upstream(None)
+    };
+
+    // Abandoned sessions would hold a connection of the tier forever.
+    let tier = state
+        .tiers
+        .read()
+        .await
+        .get(&proxy_req.consumer.tier)
+        .cloned();
+    let (idle_timeout, max_session_duration) = tier
+        .map(|tier| (tier.idle_timeout, tier.max_session_duration))
+        .unwrap_or_default();
+    let timeouts = async {
+        loop {
+            let idle_deadline = idle_timeout
+                .map(|timeout| Duration::from_millis(last_frame.load(Ordering::Relaxed)) + timeout);
+            let deadline = match (idle_deadline, max_session_duration) {
+                (Some(idle), Some(max)) => idle.min(max),
+                (Some(idle), None) => idle,
+                (None, Some(max)) => max,
+                (None, None) => return std::future::pending().await,
+            };
+            tokio::time::sleep_until((started + deadline).into()).await;
+
+            let elapsed = started.elapsed();
+            if max_session_duration.is_some_and(|max| elapsed >= max) {
+                return SessionEnd::Proxy(CloseCode::Normal, CLOSE_REASON_SESSION_EXPIRED);
+            }
+            let idle =
+                elapsed.saturating_sub(Duration::from_millis(last_frame.load(Ordering::Relaxed)));
+            if idle_timeout.is_some_and(|timeout| idle >= timeout) {
+                return SessionEnd::Proxy(CloseCode::Normal, CLOSE_REASON_IDLE_TIMEOUT);
+            }
+        }
+    };
+
+    // Half-open connections are only noticed by the proxy when a side stops answering pings.
+    let client_keepalive = keepalive(
+        client_ping_tx,
+        &client_liveness,
+        now,
+        state.config.proxy_ws_ping_interval,
+        state.config.proxy_ws_pong_timeout,
+    );
+    let instance_keepalive = keepalive(
+        instance_ping_tx,
+        &instance_liveness,
+        now,
+        state.config.ogmios_ws_ping_interval,
+        state.config.ogmios_ws_pong_timeout,
+    );
+
+    let mut client_out_done = false;
+    let mut instance_out_done = false;
+    let end = tokio::select! {
+        end = client_in => end,
+        end = instance_in => end,
+        end = timeouts => end,
+        // Sessions still live after the drain deadline are closed, so clients reconnect to
+        // another replica.
+        _ = shutdown::wait(state.shutdown.subscribe(), true) => {
+            SessionEnd::Proxy(CloseCode::Away, CLOSE_REASON_GOING_AWAY)
+        },
+        _ = client_keepalive => {
+            info!(consumer = proxy_req.consumer.to_string(), "client stopped answering pings");
+            SessionEnd::Client(None)
+        },
+        _ = instance_keepalive => {
+            info!(consumer = proxy_req.consumer.to_string(), "instance stopped answering pings");
+            SessionEnd::Upstream(None)
+        },
+        _ = &mut client_out => {
+            client_out_done = true;
+            SessionEnd::Client(None)
+        },
+        _ = &mut instance_out => {
+            instance_out_done = true;
+            SessionEnd::Upstream(None)
+        },
+    };
+    debug!(
+        consumer = proxy_req.consumer.to_string(),
+        end = ?end,
+        "session ended"
+    );
+
+    // Leaders that didn't get a response release their followers.
+    pending.lock().unwrap().clear();
+
+    // Each sink finishes once its senders are dropped, after flushing the pending frames and
+    // the close frame.
+    if let Some(frame) = end.upstream_frame() {
+        let close = instance_tx.send(Message::Close(Some(frame)));
+        let _ = tokio::time::timeout(CLOSE_TIMEOUT, close).await;
+    }
+    drop(instance_tx);
+    if !instance_out_done
+        && tokio::time::timeout(CLOSE_TIMEOUT, instance_out)
+            .await
+            .is_err()
+    {
+        error!("timeout flushing frames to instance");
+    }
+
+    if let Some(frame) = end.client_frame() {
+        let close = proxy_tx.send(Message::Close(Some(frame)));
+        let _ = tokio::time::timeout(CLOSE_TIMEOUT, close).await;
+    }
+    drop(client_tx);
+    drop(proxy_tx);
+    if !client_out_done
+        && tokio::time::timeout(CLOSE_TIMEOUT, client_out)
+            .await
+            .is_err()
+    {
+        error!("timeout flushing frames to client");
+    }
+
+    state.metrics.dec_ws_total_connection(&proxy_req);
+    proxy_req.consumer.dec_connections(state.clone()).await;
+
+    let active_connections = proxy_req
+        .consumer
+        .get_active_connections(state.clone())
+        .await;
+    info!(
+        consumer = proxy_req.consumer.to_string(),
+        active_connections, "client disconnected"
+    );
+}